@@ -0,0 +1,70 @@
+#!/usr/bin/env bash
+# Times the bench/*.mal workloads on each engine of the step4 REPL, release build, best of $RUNS
+# runs of at most $TIMEOUT seconds.
+#
+#   bench/run.sh            the working tree
+#   bench/run.sh REV...     each git revision, built in a temporary worktree
+#
+# The workloads are fed to the REPL on stdin, one form per line, so that they run on the
+# revisions before file arguments existed. An engine is skipped on a revision without its flag.
+set -euo pipefail
+
+RUNS=${RUNS:-5}
+TIMEOUT=${TIMEOUT:-60}
+root=$(git -C "$(dirname "$0")" rev-parse --show-toplevel)
+workdir=$(mktemp -d)
+trap 'git -C "$root" worktree prune; rm -rf "$workdir"' EXIT
+
+# Builds the tree at $1 as $2 and prints the path of its step4 binary, the revisions share the
+# target directory of the dependencies
+build() {
+    CARGO_TARGET_DIR=$workdir/target cargo build --quiet --release --bin step4 \
+        --manifest-path "$1/Cargo.toml" >&2
+    cp "$workdir/target/release/step4" "$workdir/$2.step4"
+    echo "$workdir/$2.step4"
+}
+
+# Best wall-clock time in seconds of `$1 $2 < $3`
+best() {
+    local best="" start end time out
+    for _ in $(seq "$RUNS"); do
+        start=$(date +%s%N)
+        # The REPL saves its history in the working directory
+        if ! out=$(cd "$workdir" && timeout "$TIMEOUT" "$1" $2 <"$3" 2>/dev/null); then
+            echo "timed out"
+            return
+        fi
+        end=$(date +%s%N)
+        if grep -q '^Error' <<<"$out"; then
+            echo "failed"
+            return
+        fi
+        time=$(((end - start) / 1000000))
+        if [ -z "$best" ] || [ "$time" -lt "$best" ]; then best=$time; fi
+    done
+    printf '%d.%03d' $((best / 1000)) $((best % 1000))
+}
+
+printf '%-12s %-14s %-12s %s\n' revision workload engine seconds
+for rev in "${@:-working-tree}"; do
+    if [ "$rev" = working-tree ]; then
+        tree=$root
+    else
+        tree=$workdir/$(git -C "$root" rev-parse --short "$rev")
+        if [ ! -d "$tree" ]; then
+            git -C "$root" worktree add --quiet --detach "$tree" "$rev"
+            # The lock file is not committed, the revisions are built with the same dependencies
+            cp "$root/Cargo.lock" "$tree/" 2>/dev/null || true
+        fi
+    fi
+    bin=$(build "$tree" "$rev")
+    for workload in "$root"/bench/*.mal; do
+        for engine in "" --vm --closures; do
+            if [ -n "$engine" ] && ! grep -q -- "\"$engine\"" "$tree/src/bin/step4.rs"; then
+                continue
+            fi
+            printf '%-12s %-14s %-12s %s\n' "$rev" "$(basename "$workload")" \
+                "${engine:-tree-walker}" "$(best "$bin" "$engine" "$workload")"
+        done
+    done
+done
@@ -0,0 +1,28 @@
+//! Sets `MALC_BUILD_ID` to a hash of the library sources, so that the `.malc` caches written by
+//! another build of the compiler or the VM are ignored.
+
+use std::{fs, path::Path};
+
+fn main() {
+    println!("cargo:rerun-if-changed=src");
+    let mut files: Vec<_> = fs::read_dir("src")
+        .expect("Cannot list src")
+        .map(|entry| entry.expect("Cannot list src").path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "rs"))
+        .collect();
+    files.sort();
+
+    // FNV-1a over the names and contents, like the hash of the cached sources
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for file in &files {
+        let name = file
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+        let source = fs::read(Path::new(file)).expect("Cannot read a source file");
+        for b in name.bytes().chain(source) {
+            hash = (hash ^ b as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+    println!("cargo:rustc-env=MALC_BUILD_ID={:016x}", hash);
+}
@@ -1,130 +1,1662 @@
-use std::rc::Rc;
+use std::{cmp::Ordering, path::Path, rc::Rc};
 
-use crate::{printer::pr_str, types::MalValue, Result};
-use anyhow::anyhow;
+use crate::{
+    budget, gc, host, malc, namespace, optimizer,
+    printer::{pr_str, print_str},
+    reader,
+    sandbox::Capability,
+    symbol::Symbol,
+    types::{
+        check_comparable, compare_with, key_value, map_key, merge_sort_by, MalMap, MalValue,
+        SortedMap,
+    },
+    Result,
+};
+use anyhow::{anyhow, Context};
 use itertools::Itertools;
+use regex::Regex;
 
-pub fn add(args: &Vec<MalValue>) -> Result<MalValue> {
-    match &args[..] {
-        [MalValue::Number(l), MalValue::Number(r)] => Ok(MalValue::Number(l + r)),
-        [MalValue::String(l), MalValue::String(r)] => Ok(MalValue::String(l.to_owned() + r)),
-        a => Err(anyhow!(
-            "Cannot add args: {:?}",
-            a.iter().map(|v| pr_str(v)).collect::<Vec<_>>()
+/// Function of the core namespace
+#[derive(Debug, Clone, Copy)]
+pub struct Builtin {
+    pub name: &'static str,
+    pub func: fn(&[MalValue]) -> Result<MalValue>,
+    /// Denied to sandboxed code unless allowed, see [crate::sandbox]
+    pub needs: Option<Capability>,
+}
+
+impl Builtin {
+    const fn pure(name: &'static str, func: fn(&[MalValue]) -> Result<MalValue>) -> Self {
+        Builtin {
+            name,
+            func,
+            needs: None,
+        }
+    }
+
+    const fn io(name: &'static str, func: fn(&[MalValue]) -> Result<MalValue>) -> Self {
+        Builtin {
+            name,
+            func,
+            needs: Some(Capability::Io),
+        }
+    }
+}
+
+/// Every builtin, bound in the core environment
+pub const BUILTINS: &[Builtin] = &[
+    Builtin::pure("+", add),
+    Builtin::pure("-", sub),
+    Builtin::pure("*", mult),
+    Builtin::pure("/", div),
+    Builtin::pure("inc", inc),
+    Builtin::pure("dec", dec),
+    Builtin::pure("mod", modulo),
+    Builtin::pure("rem", rem),
+    Builtin::pure("quot", quot),
+    Builtin::pure("abs", abs),
+    Builtin::pure("min", min),
+    Builtin::pure("max", max),
+    Builtin::pure("pow", pow),
+    Builtin::io("prn", prn),
+    Builtin::pure("list", list),
+    Builtin::pure("list?", is_list),
+    Builtin::pure("empty?", is_empty),
+    Builtin::pure("count", count),
+    Builtin::pure("cons", cons),
+    Builtin::pure("concat", concat),
+    Builtin::pure("vec", vec),
+    Builtin::pure("first", first),
+    Builtin::pure("rest", rest),
+    Builtin::pure("nth", nth),
+    Builtin::pure("last", last),
+    Builtin::pure("conj", conj),
+    Builtin::pure("seq", seq),
+    Builtin::pure("map", map),
+    Builtin::pure("filter", filter),
+    Builtin::pure("reduce", reduce),
+    Builtin::pure("apply", apply),
+    Builtin::pure("take", take_first),
+    Builtin::pure("drop", drop_first),
+    Builtin::pure("reverse", reverse),
+    Builtin::pure("range", range),
+    Builtin::pure("some", some),
+    Builtin::pure("every?", every),
+    Builtin::pure("sorted-map", sorted_map),
+    Builtin::pure("sorted-map-by", sorted_map_by),
+    Builtin::pure("hash-map", hash_map),
+    Builtin::pure("assoc", assoc),
+    Builtin::pure("dissoc", dissoc),
+    Builtin::pure("get", get),
+    Builtin::pure("get-in", get_in),
+    Builtin::pure("assoc-in", assoc_in),
+    Builtin::pure("update", update),
+    Builtin::pure("update-in", update_in),
+    Builtin::pure("contains?", contains),
+    Builtin::pure("keys", keys),
+    Builtin::pure("vals", vals),
+    Builtin::pure("merge", merge),
+    Builtin::pure("merge-with", merge_with),
+    Builtin::pure("select-keys", select_keys),
+    Builtin::pure("zipmap", zipmap),
+    Builtin::pure("str", str),
+    Builtin::pure("subs", subs),
+    Builtin::pure("split", split),
+    Builtin::pure("join", join),
+    Builtin::pure("upper-case", upper_case),
+    Builtin::pure("lower-case", lower_case),
+    Builtin::pure("trim", trim),
+    Builtin::pure("starts-with?", starts_with),
+    Builtin::pure("ends-with?", ends_with),
+    Builtin::pure("includes?", includes),
+    Builtin::pure("replace", replace),
+    Builtin::pure("index-of", index_of),
+    Builtin::pure("format", format),
+    Builtin::pure("re-pattern", re_pattern),
+    Builtin::pure("re-find", re_find),
+    Builtin::pure("re-matches", re_matches),
+    Builtin::pure("re-seq", re_seq),
+    Builtin::pure("re-replace", re_replace),
+    Builtin::pure("nil?", is_nil),
+    Builtin::pure("true?", is_true),
+    Builtin::pure("false?", is_false),
+    Builtin::pure("number?", is_number),
+    Builtin::pure("string?", is_string),
+    Builtin::pure("symbol?", is_symbol),
+    Builtin::pure("keyword?", is_keyword),
+    Builtin::pure("vector?", is_vector),
+    Builtin::pure("map?", is_map),
+    Builtin::pure("sequential?", is_sequential),
+    Builtin::pure("fn?", is_fn),
+    Builtin::pure("macro?", is_macro),
+    Builtin::pure("atom?", is_atom),
+    Builtin::pure("symbol", symbol),
+    Builtin::pure("keyword", keyword),
+    Builtin::pure("name", name),
+    Builtin::pure("str->int", str_to_int),
+    Builtin::pure("int->str", int_to_str),
+    Builtin::pure("vector", vector),
+    Builtin::pure("=", eq),
+    Builtin::pure("<", lt),
+    Builtin::pure("<=", lt_eq),
+    Builtin::pure(">", gt),
+    Builtin::pure(">=", gt_eq),
+    Builtin::pure("compare", compare),
+    Builtin::pure("sort", sort),
+    Builtin::pure("sort-by", sort_by),
+    Builtin::pure("gc", gc),
+    Builtin::pure("heap-stats", heap_stats),
+    Builtin::io("load-file", load_file),
+    Builtin::io("optimize", optimize),
+    Builtin::pure("rand-int", rand_int),
+    Builtin::pure("time-ms", time_ms),
+];
+
+/// Sum of the numbers, or concatenation of the strings
+pub fn add(args: &[MalValue]) -> Result<MalValue> {
+    if !args.is_empty() && args.iter().all(|a| matches!(a, MalValue::String(_))) {
+        let strs = args.iter().map(|a| match a {
+            MalValue::String(s) => &**s,
+            _ => "",
+        });
+        return Ok(MalValue::String(strs.collect::<String>().into()));
+    }
+    fold_numbers("+", 0, args, i64::checked_add)
+}
+
+/// First number minus the others, or the negation of a single one
+pub fn sub(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [] => Err(anyhow!("- needs at least one argument")),
+        [n] => checked("-", number("-", n)?.checked_neg()),
+        [first, rest @ ..] => fold_numbers("-", number("-", first)?, rest, i64::checked_sub),
+    }
+}
+
+pub fn mult(args: &[MalValue]) -> Result<MalValue> {
+    fold_numbers("*", 1, args, i64::checked_mul)
+}
+
+/// First number divided by the others, or the integer reciprocal of a single one
+pub fn div(args: &[MalValue]) -> Result<MalValue> {
+    let (first, divisors) = match args {
+        [] => return Err(anyhow!("/ needs at least one argument")),
+        [_] => (1, args),
+        [first, rest @ ..] => (number("/", first)?, rest),
+    };
+    if divisors.iter().any(|d| *d == MalValue::Number(0)) {
+        return Err(anyhow!("Divide by zero"));
+    }
+    fold_numbers("/", first, divisors, i64::checked_div)
+}
+
+pub fn inc(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [n] => checked("inc", number("inc", n)?.checked_add(1)),
+        _ => Err(anyhow!("inc needs one number")),
+    }
+}
+
+pub fn dec(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [n] => checked("dec", number("dec", n)?.checked_sub(1)),
+        _ => Err(anyhow!("dec needs one number")),
+    }
+}
+
+/// Quotient rounded towards zero
+pub fn quot(args: &[MalValue]) -> Result<MalValue> {
+    let (n, d) = dividend_divisor("quot", args)?;
+    checked("quot", n.checked_div(d))
+}
+
+/// Remainder of [quot], with the sign of the dividend
+pub fn rem(args: &[MalValue]) -> Result<MalValue> {
+    let (n, d) = dividend_divisor("rem", args)?;
+    checked("rem", n.checked_rem(d))
+}
+
+/// Modulus, with the sign of the divisor
+pub fn modulo(args: &[MalValue]) -> Result<MalValue> {
+    let (n, d) = dividend_divisor("mod", args)?;
+    let Some(r) = n.checked_rem(d) else {
+        return checked("mod", None);
+    };
+    match r != 0 && (r < 0) != (d < 0) {
+        true => Ok(MalValue::Number(r + d)),
+        false => Ok(MalValue::Number(r)),
+    }
+}
+
+pub fn abs(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [n] => checked("abs", number("abs", n)?.checked_abs()),
+        _ => Err(anyhow!("abs needs one number")),
+    }
+}
+
+pub fn min(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [] => Err(anyhow!("min needs at least one number")),
+        [first, rest @ ..] => {
+            fold_numbers("min", number("min", first)?, rest, |a, b| Some(a.min(b)))
+        }
+    }
+}
+
+pub fn max(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [] => Err(anyhow!("max needs at least one number")),
+        [first, rest @ ..] => {
+            fold_numbers("max", number("max", first)?, rest, |a, b| Some(a.max(b)))
+        }
+    }
+}
+
+/// Base raised to a non negative exponent
+pub fn pow(args: &[MalValue]) -> Result<MalValue> {
+    let (base, exp) = match args {
+        [base, exp] => (number("pow", base)?, number("pow", exp)?),
+        _ => return Err(anyhow!("pow needs a base and an exponent")),
+    };
+    match u32::try_from(exp) {
+        Ok(exp) => checked("pow", base.checked_pow(exp)),
+        Err(_) if exp < 0 => Err(anyhow!("pow needs a non negative exponent, got {}", exp)),
+        Err(_) => Err(anyhow!("Integer overflow in pow")),
+    }
+}
+
+/// Number of an argument of `name`, erroring on anything else
+fn number(name: &str, arg: &MalValue) -> Result<i64> {
+    match arg {
+        MalValue::Number(n) => Ok(*n),
+        a => Err(anyhow!("Cannot {} on: {}", name, pr_str(a))),
+    }
+}
+
+/// Folds the numbers with a checked operation, [None] being an overflow
+fn fold_numbers(
+    name: &str,
+    init: i64,
+    args: &[MalValue],
+    op: fn(i64, i64) -> Option<i64>,
+) -> Result<MalValue> {
+    let mut acc = init;
+    for arg in args {
+        acc = op(acc, number(name, arg)?).ok_or_else(|| anyhow!("Integer overflow in {}", name))?;
+    }
+    Ok(MalValue::Number(acc))
+}
+
+fn dividend_divisor(name: &str, args: &[MalValue]) -> Result<(i64, i64)> {
+    match args {
+        [n, d] => match (number(name, n)?, number(name, d)?) {
+            (_, 0) => Err(anyhow!("Divide by zero")),
+            nd => Ok(nd),
+        },
+        _ => Err(anyhow!("{} needs a dividend and a divisor", name)),
+    }
+}
+
+/// Result of a checked operation, [None] being an overflow
+fn checked(name: &str, result: Option<i64>) -> Result<MalValue> {
+    result
+        .map(MalValue::Number)
+        .ok_or_else(|| anyhow!("Integer overflow in {}", name))
+}
+
+pub fn prn(args: &[MalValue]) -> Result<MalValue> {
+    let str: String = args.iter().map(pr_str).join(" ");
+    host::println(&str);
+    Ok(MalValue::Nil)
+}
+
+pub fn list(args: &[MalValue]) -> Result<MalValue> {
+    Ok(MalValue::List(Rc::new(args.to_vec())))
+}
+
+pub fn is_list(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [MalValue::List(_)] => Ok(MalValue::True),
+        [_] => Ok(MalValue::False),
+        _ => Err(anyhow!(
+            "Invalid number of args for list?, got {}",
+            args.len()
         )),
     }
 }
 
-pub fn sub(args: &Vec<MalValue>) -> Result<MalValue> {
-    match &args[..] {
-        [MalValue::Number(l), MalValue::Number(r)] => Ok(MalValue::Number(l - r)),
+pub fn is_empty(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [coll] => Ok((count_items("empty?", coll)? == 0).into()),
         a => Err(anyhow!(
-            "Cannot sub args: {:?}",
-            a.iter().map(|v| pr_str(v)).collect::<Vec<_>>()
+            "Cannot check is empty, too many args: {:?}",
+            a.iter().map(pr_str).collect::<Vec<_>>()
         )),
     }
 }
 
-pub fn mult(args: &Vec<MalValue>) -> Result<MalValue> {
-    match &args[..] {
-        [MalValue::Number(l), MalValue::Number(r)] => Ok(MalValue::Number(l * r)),
+pub fn count(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [coll] => Ok(MalValue::Number(count_items("count", coll)? as i64)),
         a => Err(anyhow!(
-            "Cannot mult args: {:?}",
-            a.iter().map(|v| pr_str(v)).collect::<Vec<_>>()
+            "count needs one collection: {:?}",
+            a.iter().map(pr_str).collect::<Vec<_>>()
         )),
     }
 }
 
-pub fn div(args: &Vec<MalValue>) -> Result<MalValue> {
-    match &args[..] {
-        [MalValue::Number(l), MalValue::Number(r)] => Ok(MalValue::Number(l / r)),
+fn count_items(name: &str, coll: &MalValue) -> Result<usize> {
+    match coll {
+        MalValue::List(l) | MalValue::Vec(l) => Ok(l.len()),
+        MalValue::String(s) => Ok(s.chars().count()),
+        MalValue::Nil => Ok(0),
+        c => match c.map_entries() {
+            Some(m) => Ok(m.len()),
+            None => Err(anyhow!("Cannot {} {}", name, pr_str(c))),
+        },
+    }
+}
+
+/// Items of a list, vector or nil, entries of a map as `[key value]` vectors, or characters of
+/// a string as strings of one character
+fn sequence(name: &str, coll: &MalValue) -> Result<Rc<Vec<MalValue>>> {
+    match coll {
+        MalValue::List(l) | MalValue::Vec(l) => Ok(l.clone()),
+        MalValue::Nil => Ok(Rc::default()),
+        MalValue::String(s) => Ok(Rc::new(
+            s.chars()
+                .map(|c| MalValue::String(c.to_string().into()))
+                .collect(),
+        )),
+        c => match c.map_entries() {
+            Some(m) => Ok(Rc::new(
+                m.iter()
+                    .map(|(k, v)| MalValue::Vec(Rc::new(vec![key_value(k), v.clone()])))
+                    .collect(),
+            )),
+            None => Err(anyhow!("Cannot {} {}", name, pr_str(c))),
+        },
+    }
+}
+
+fn list_of(items: impl IntoIterator<Item = MalValue>) -> MalValue {
+    MalValue::List(Rc::new(items.into_iter().collect()))
+}
+
+fn is_truthy(val: &MalValue) -> bool {
+    !matches!(val, MalValue::False | MalValue::Nil)
+}
+
+/// List of the value followed by the items of the collection
+pub fn cons(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [val, coll] => {
+            let items = sequence("cons", coll)?;
+            Ok(list_of(
+                std::iter::once(val.clone()).chain(items.iter().cloned()),
+            ))
+        }
+        _ => Err(anyhow!("cons needs a value and a collection")),
+    }
+}
+
+/// List of the items of all the collections
+pub fn concat(args: &[MalValue]) -> Result<MalValue> {
+    let mut items = vec![];
+    for coll in args {
+        items.extend(sequence("concat", coll)?.iter().cloned());
+    }
+    Ok(MalValue::List(Rc::new(items)))
+}
+
+pub fn vec(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [MalValue::Vec(v)] => Ok(MalValue::Vec(v.clone())),
+        [coll] => Ok(MalValue::Vec(Rc::new(sequence("vec", coll)?.to_vec()))),
+        _ => Err(anyhow!("vec needs one collection")),
+    }
+}
+
+/// First item, nil for an empty collection
+pub fn first(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [coll] => Ok(sequence("first", coll)?
+            .first()
+            .cloned()
+            .unwrap_or(MalValue::Nil)),
+        _ => Err(anyhow!("first needs one collection")),
+    }
+}
+
+/// List of the items after the first one
+pub fn rest(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [coll] => Ok(list_of(sequence("rest", coll)?.iter().skip(1).cloned())),
+        _ => Err(anyhow!("rest needs one collection")),
+    }
+}
+
+/// Item at the index, the default or an error when it is out of bounds
+pub fn nth(args: &[MalValue]) -> Result<MalValue> {
+    let (coll, index, default) = match args {
+        [coll, MalValue::Number(i)] => (coll, *i, None),
+        [coll, MalValue::Number(i), default] => (coll, *i, Some(default)),
+        _ => {
+            return Err(anyhow!(
+                "nth needs a collection, an index and maybe a default"
+            ))
+        }
+    };
+    let items = sequence("nth", coll)?;
+    let item = usize::try_from(index).ok().and_then(|i| items.get(i));
+    match (item, default) {
+        (Some(item), _) => Ok(item.clone()),
+        (None, Some(default)) => Ok(default.clone()),
+        (None, None) => Err(anyhow!(
+            "Index {} out of bounds for {} items",
+            index,
+            items.len()
+        )),
+    }
+}
+
+/// Last item, nil for an empty collection
+pub fn last(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [coll] => Ok(sequence("last", coll)?
+            .last()
+            .cloned()
+            .unwrap_or(MalValue::Nil)),
+        _ => Err(anyhow!("last needs one collection")),
+    }
+}
+
+/// Adds the values where the collection grows: at the front of a list, at the end of a vector,
+/// as entries of a map from `[key value]` vectors or maps
+pub fn conj(args: &[MalValue]) -> Result<MalValue> {
+    let (coll, vals) = args
+        .split_first()
+        .ok_or_else(|| anyhow!("conj needs a collection"))?;
+    match coll {
+        MalValue::List(_) | MalValue::Nil => {
+            let items = sequence("conj", coll)?;
+            Ok(list_of(vals.iter().rev().chain(items.iter()).cloned()))
+        }
+        MalValue::Vec(v) => Ok(MalValue::Vec(Rc::new(
+            v.iter().chain(vals).cloned().collect(),
+        ))),
+        MalValue::Map(m) => {
+            let mut map = (**m).clone();
+            map.extend(map_entries("conj", vals)?);
+            Ok(MalValue::Map(Rc::new(map)))
+        }
+        MalValue::SortedMap(m) => {
+            let entries = m.entries.clone().into_iter();
+            let map = SortedMap::new(entries.chain(map_entries("conj", vals)?), m.by.clone())?;
+            Ok(MalValue::SortedMap(Rc::new(map)))
+        }
+        c => Err(anyhow!("Cannot conj to {}", pr_str(c))),
+    }
+}
+
+/// Entries by printed key of `[key value]` vectors and maps
+fn map_entries(name: &str, vals: &[MalValue]) -> Result<Vec<(String, MalValue)>> {
+    let mut entries = vec![];
+    for val in vals {
+        match val {
+            MalValue::Vec(kv) | MalValue::List(kv) if kv.len() == 2 => {
+                entries.push((map_key(&kv[0])?, kv[1].clone()))
+            }
+            MalValue::Nil => (),
+            v => match v.map_entries() {
+                Some(m) => entries.extend(m.iter().map(|(k, v)| (k.clone(), v.clone()))),
+                None => return Err(anyhow!("Cannot {} {} to a map", name, pr_str(v))),
+            },
+        }
+    }
+    Ok(entries)
+}
+
+/// List of the items, nil for an empty collection
+pub fn seq(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [coll] => match sequence("seq", coll)? {
+            items if items.is_empty() => Ok(MalValue::Nil),
+            items => Ok(MalValue::List(items)),
+        },
+        _ => Err(anyhow!("seq needs one collection")),
+    }
+}
+
+/// List of the function applied to the items of the collections, taken together up to the
+/// end of the shortest one
+pub fn map(args: &[MalValue]) -> Result<MalValue> {
+    let (f, colls) = match args {
+        [f, colls @ ..] if !colls.is_empty() => (f, colls),
+        _ => return Err(anyhow!("map needs a function and collections")),
+    };
+    let colls = colls
+        .iter()
+        .map(|c| sequence("map", c))
+        .collect::<Result<Vec<_>>>()?;
+    let len = colls.iter().map(|c| c.len()).min().unwrap_or(0);
+    let vals = (0..len)
+        .map(|i| f.apply(Rc::new(colls.iter().map(|c| c[i].clone()).collect())))
+        .collect::<Result<_>>()?;
+    Ok(MalValue::List(Rc::new(vals)))
+}
+
+/// List of the items for which the predicate is truthy
+pub fn filter(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [pred, coll] => {
+            let mut items = vec![];
+            for item in sequence("filter", coll)?.iter() {
+                if is_truthy(&pred.apply(Rc::new(vec![item.clone()]))?) {
+                    items.push(item.clone());
+                }
+            }
+            Ok(MalValue::List(Rc::new(items)))
+        }
+        _ => Err(anyhow!("filter needs a predicate and a collection")),
+    }
+}
+
+/// Combines the items with the function, from the initial value or else the first item
+pub fn reduce(args: &[MalValue]) -> Result<MalValue> {
+    let (f, init, items) = match args {
+        [f, coll] => {
+            let items = sequence("reduce", coll)?;
+            match items.split_first() {
+                None => return f.apply(Rc::default()),
+                Some((first, rest)) => (f, first.clone(), rest.to_vec()),
+            }
+        }
+        [f, init, coll] => (f, init.clone(), sequence("reduce", coll)?.to_vec()),
+        _ => {
+            return Err(anyhow!(
+                "reduce needs a function, maybe a value and a collection"
+            ))
+        }
+    };
+    items
+        .into_iter()
+        .try_fold(init, |acc, item| f.apply(Rc::new(vec![acc, item])))
+}
+
+/// Calls the function with the arguments followed by the items of the last one
+pub fn apply(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [f, vals @ .., coll] => {
+            let items = sequence("apply", coll)?;
+            f.apply(Rc::new(vals.iter().chain(items.iter()).cloned().collect()))
+        }
+        _ => Err(anyhow!("apply needs a function and a collection")),
+    }
+}
+
+/// List of the first `n` items
+pub fn take_first(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [MalValue::Number(n), coll] => {
+            let n = usize::try_from(*n).unwrap_or(0);
+            Ok(list_of(sequence("take", coll)?.iter().take(n).cloned()))
+        }
+        _ => Err(anyhow!("take needs a number and a collection")),
+    }
+}
+
+/// List of the items after the first `n` ones
+pub fn drop_first(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [MalValue::Number(n), coll] => {
+            let n = usize::try_from(*n).unwrap_or(0);
+            Ok(list_of(sequence("drop", coll)?.iter().skip(n).cloned()))
+        }
+        _ => Err(anyhow!("drop needs a number and a collection")),
+    }
+}
+
+pub fn reverse(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [coll] => Ok(list_of(sequence("reverse", coll)?.iter().rev().cloned())),
+        _ => Err(anyhow!("reverse needs one collection")),
+    }
+}
+
+/// List of the numbers from the start, 0 by default, up to the end excluded, by the step
+pub fn range(args: &[MalValue]) -> Result<MalValue> {
+    let (start, end, step) = match args {
+        [end] => (0, number("range", end)?, 1),
+        [start, end] => (number("range", start)?, number("range", end)?, 1),
+        [start, end, step] => (
+            number("range", start)?,
+            number("range", end)?,
+            number("range", step)?,
+        ),
+        _ => return Err(anyhow!("range needs an end, and maybe a start and a step")),
+    };
+    if step == 0 {
+        return Err(anyhow!("range needs a step other than 0"));
+    }
+    // Any span between two i64 fits in an i128
+    let span = (i128::from(end) - i128::from(start)) * i128::from(step.signum());
+    let stride = i128::from(step.unsigned_abs());
+    let len = match span > 0 {
+        true => (span + stride - 1) / stride,
+        false => 0,
+    };
+    let len = usize::try_from(len).unwrap_or(usize::MAX);
+    budget::check_size(len)?;
+    let nums = std::iter::successors(Some(start), |n| n.checked_add(step)).take(len);
+    Ok(list_of(nums.map(MalValue::Number)))
+}
+
+/// First truthy value of the predicate on the items, nil if there is none
+pub fn some(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [pred, coll] => {
+            for item in sequence("some", coll)?.iter() {
+                let val = pred.apply(Rc::new(vec![item.clone()]))?;
+                if is_truthy(&val) {
+                    return Ok(val);
+                }
+            }
+            Ok(MalValue::Nil)
+        }
+        _ => Err(anyhow!("some needs a predicate and a collection")),
+    }
+}
+
+/// Whether the predicate is truthy on every item
+pub fn every(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [pred, coll] => {
+            for item in sequence("every?", coll)?.iter() {
+                if !is_truthy(&pred.apply(Rc::new(vec![item.clone()]))?) {
+                    return Ok(MalValue::False);
+                }
+            }
+            Ok(MalValue::True)
+        }
+        _ => Err(anyhow!("every? needs a predicate and a collection")),
+    }
+}
+
+/// Map iterated in the order of its keys, the strings before the keywords, from key value pairs
+pub fn sorted_map(args: &[MalValue]) -> Result<MalValue> {
+    new_sorted_map(args, None)
+}
+
+/// Map iterated in the order of its keys given by the comparator, from key value pairs
+pub fn sorted_map_by(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [by, pairs @ ..] => new_sorted_map(pairs, Some(by.clone())),
+        [] => Err(anyhow!("sorted-map-by needs a comparator")),
+    }
+}
+
+fn new_sorted_map(pairs: &[MalValue], by: Option<MalValue>) -> Result<MalValue> {
+    if !pairs.len().is_multiple_of(2) {
+        return Err(anyhow!(
+            "A sorted map needs an even number of key/value arguments, got {}",
+            pairs.len()
+        ));
+    }
+    let entries = pairs
+        .iter()
+        .tuples()
+        .map(|(k, v)| Ok((map_key(k)?, v.clone())))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(MalValue::SortedMap(Rc::new(SortedMap::new(entries, by)?)))
+}
+
+/// Map of key value pairs
+pub fn hash_map(args: &[MalValue]) -> Result<MalValue> {
+    if !args.len().is_multiple_of(2) {
+        return Err(anyhow!(
+            "hash-map needs an even number of key/value arguments, got {}",
+            args.len()
+        ));
+    }
+    reader::hash_map(args.to_vec())
+}
+
+/// Map or sorted map with the entries replaced, keeping a sorted map sorted
+fn with_entries(coll: &MalValue, entries: MalMap) -> Result<MalValue> {
+    match coll {
+        MalValue::SortedMap(m) => Ok(MalValue::SortedMap(Rc::new(SortedMap::new(
+            entries,
+            m.by.clone(),
+        )?))),
+        _ => Ok(MalValue::Map(Rc::new(entries))),
+    }
+}
+
+/// Entries of a map, none for nil
+fn entries(name: &str, coll: &MalValue) -> Result<MalMap> {
+    match coll {
+        MalValue::Nil => Ok(MalMap::default()),
+        c => match c.map_entries() {
+            Some(m) => Ok(m.clone()),
+            None => Err(anyhow!("Cannot {} on {}", name, pr_str(c))),
+        },
+    }
+}
+
+/// Value of the key in a map or of the index in a vector
+fn lookup(coll: &MalValue, key: &MalValue) -> Option<MalValue> {
+    match (coll, key) {
+        (MalValue::Vec(v), MalValue::Number(i)) => {
+            usize::try_from(*i).ok().and_then(|i| v.get(i)).cloned()
+        }
+        (c, k) => c.map_entries()?.get(&map_key(k).ok()?).cloned(),
+    }
+}
+
+/// Map with the value of the key, or vector with the value at the index, up to its length
+fn assoc_one(coll: &MalValue, key: &MalValue, val: MalValue) -> Result<MalValue> {
+    match (coll, key) {
+        (MalValue::Vec(v), MalValue::Number(i)) => {
+            let mut items = v.to_vec();
+            match usize::try_from(*i) {
+                Ok(i) if i < items.len() => items[i] = val,
+                Ok(i) if i == items.len() => items.push(val),
+                _ => return Err(anyhow!("Index {} out of bounds for {} items", i, v.len())),
+            }
+            Ok(MalValue::Vec(Rc::new(items)))
+        }
+        (c, k) => {
+            let mut map = entries("assoc", c)?;
+            map.insert(map_key(k)?, val);
+            with_entries(c, map)
+        }
+    }
+}
+
+/// Map with the values of the keys, from key value pairs
+pub fn assoc(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [coll, pairs @ ..] if !pairs.is_empty() && pairs.len().is_multiple_of(2) => pairs
+            .iter()
+            .tuples()
+            .try_fold(coll.clone(), |coll, (k, v)| assoc_one(&coll, k, v.clone())),
+        _ => Err(anyhow!("assoc needs a map and key value pairs")),
+    }
+}
+
+/// Map without the keys
+pub fn dissoc(args: &[MalValue]) -> Result<MalValue> {
+    let (coll, keys) = args
+        .split_first()
+        .ok_or_else(|| anyhow!("dissoc needs a map"))?;
+    let mut map = entries("dissoc", coll)?;
+    for key in keys {
+        if let Ok(key) = map_key(key) {
+            map.shift_remove(&key);
+        }
+    }
+    match coll {
+        MalValue::Nil => Ok(MalValue::Nil),
+        c => with_entries(c, map),
+    }
+}
+
+/// Value of the key, or the default, nil by default
+pub fn get(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [coll, key] => Ok(lookup(coll, key).unwrap_or(MalValue::Nil)),
+        [coll, key, default] => Ok(lookup(coll, key).unwrap_or_else(|| default.clone())),
+        _ => Err(anyhow!("get needs a map, a key and maybe a default")),
+    }
+}
+
+/// Value at the path of keys in nested maps, or the default, nil by default
+pub fn get_in(args: &[MalValue]) -> Result<MalValue> {
+    let (coll, path, default) = match args {
+        [coll, path] => (coll, path, MalValue::Nil),
+        [coll, path, default] => (coll, path, default.clone()),
+        _ => return Err(anyhow!("get-in needs a map, a path and maybe a default")),
+    };
+    let val = sequence("get-in", path)?
+        .iter()
+        .try_fold(coll.clone(), |coll, key| lookup(&coll, key));
+    Ok(val.unwrap_or(default))
+}
+
+/// Nested maps with the value at the path, creating the missing maps
+pub fn assoc_in(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [coll, path, val] => {
+            update_path(coll, &sequence("assoc-in", path)?, &mut |_| Ok(val.clone()))
+        }
+        _ => Err(anyhow!("assoc-in needs a map, a path and a value")),
+    }
+}
+
+/// Map with the value of the key replaced by the function of it and the arguments
+pub fn update(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [coll, key, f, fargs @ ..] => update_path(coll, std::slice::from_ref(key), &mut |old| {
+            f.apply(Rc::new(
+                std::iter::once(old).chain(fargs.iter().cloned()).collect(),
+            ))
+        }),
+        _ => Err(anyhow!("update needs a map, a key and a function")),
+    }
+}
+
+/// Nested maps with the value at the path replaced by the function of it and the arguments
+pub fn update_in(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [coll, path, f, fargs @ ..] => {
+            update_path(coll, &sequence("update-in", path)?, &mut |old| {
+                f.apply(Rc::new(
+                    std::iter::once(old).chain(fargs.iter().cloned()).collect(),
+                ))
+            })
+        }
+        _ => Err(anyhow!("update-in needs a map, a path and a function")),
+    }
+}
+
+fn update_path(
+    coll: &MalValue,
+    path: &[MalValue],
+    f: &mut dyn FnMut(MalValue) -> Result<MalValue>,
+) -> Result<MalValue> {
+    match path {
+        [] => Err(anyhow!("Cannot update an empty path")),
+        [key] => assoc_one(coll, key, f(lookup(coll, key).unwrap_or(MalValue::Nil))?),
+        [key, rest @ ..] => {
+            let inner = lookup(coll, key).unwrap_or(MalValue::Nil);
+            assoc_one(coll, key, update_path(&inner, rest, f)?)
+        }
+    }
+}
+
+/// Whether the map has the key, or the vector the index
+pub fn contains(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [MalValue::Vec(v), MalValue::Number(i)] => {
+            Ok(usize::try_from(*i).is_ok_and(|i| i < v.len()).into())
+        }
+        [MalValue::Nil | MalValue::Vec(_), _] => Ok(MalValue::False),
+        [coll, key] => {
+            let map = entries("contains?", coll)?;
+            Ok(map_key(key).is_ok_and(|k| map.contains_key(&k)).into())
+        }
+        _ => Err(anyhow!("contains? needs a map and a key")),
+    }
+}
+
+/// List of the keys of the map, nil when it is empty
+pub fn keys(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [coll] => match entries("keys", coll)? {
+            m if m.is_empty() => Ok(MalValue::Nil),
+            m => Ok(list_of(m.keys().map(|k| key_value(k)))),
+        },
+        _ => Err(anyhow!("keys needs a map")),
+    }
+}
+
+/// List of the values of the map, nil when it is empty
+pub fn vals(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [coll] => match entries("vals", coll)? {
+            m if m.is_empty() => Ok(MalValue::Nil),
+            m => Ok(list_of(m.into_values())),
+        },
+        _ => Err(anyhow!("vals needs a map")),
+    }
+}
+
+/// Entries of the maps, the ones of the last map winning, nil when all of them are nil
+pub fn merge(args: &[MalValue]) -> Result<MalValue> {
+    merge_maps("merge", args, None)
+}
+
+/// Entries of the maps, the values of the keys in several maps combined with the function
+pub fn merge_with(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [f, maps @ ..] => merge_maps("merge-with", maps, Some(f)),
+        _ => Err(anyhow!("merge-with needs a function")),
+    }
+}
+
+fn merge_maps(name: &str, maps: &[MalValue], f: Option<&MalValue>) -> Result<MalValue> {
+    let Some(start) = maps.iter().position(|m| *m != MalValue::Nil) else {
+        return Ok(MalValue::Nil);
+    };
+    let first = &maps[start];
+    let mut merged = entries(name, first)?;
+    for map in &maps[start + 1..] {
+        for (k, v) in entries(name, map)? {
+            let v = match (f, merged.get(&k)) {
+                (Some(f), Some(old)) => f.apply(Rc::new(vec![old.clone(), v]))?,
+                _ => v,
+            };
+            merged.insert(k, v);
+        }
+    }
+    with_entries(first, merged)
+}
+
+/// Map of the entries of the keys found in the map
+pub fn select_keys(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [coll, keys] => {
+            let map = entries("select-keys", coll)?;
+            let selected = sequence("select-keys", keys)?
+                .iter()
+                .filter_map(|k| map_key(k).ok())
+                .filter_map(|k| Some((k.clone(), map.get(&k)?.clone())))
+                .collect();
+            Ok(MalValue::Map(Rc::new(selected)))
+        }
+        _ => Err(anyhow!("select-keys needs a map and keys")),
+    }
+}
+
+/// Map of the keys to the values, up to the end of the shortest one
+pub fn zipmap(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [keys, vals] => {
+            let (keys, vals) = (sequence("zipmap", keys)?, sequence("zipmap", vals)?);
+            let map = keys
+                .iter()
+                .zip(vals.iter())
+                .map(|(k, v)| Ok((map_key(k)?, v.clone())))
+                .collect::<Result<_>>()?;
+            Ok(MalValue::Map(Rc::new(map)))
+        }
+        _ => Err(anyhow!("zipmap needs keys and values")),
+    }
+}
+
+/// Concatenation of the values printed with the strings as their contents
+pub fn str(args: &[MalValue]) -> Result<MalValue> {
+    Ok(MalValue::String(
+        args.iter().map(print_str).collect::<String>().into(),
+    ))
+}
+
+/// String of the argument of `name`, erroring on anything else
+fn string<'a>(name: &str, arg: &'a MalValue) -> Result<&'a str> {
+    match arg {
+        MalValue::String(s) => Ok(s),
+        a => Err(anyhow!("Cannot {} on: {}", name, pr_str(a))),
+    }
+}
+
+/// Byte offset of the character at the index, up to the end of the string
+fn char_offset(s: &str, index: i64) -> Result<usize> {
+    let offset = usize::try_from(index)
+        .ok()
+        .and_then(|i| s.char_indices().map(|(o, _)| o).chain([s.len()]).nth(i));
+    offset.ok_or_else(|| {
+        anyhow!(
+            "Index {} out of bounds for {} characters",
+            index,
+            s.chars().count()
+        )
+    })
+}
+
+/// Characters from the start index up to the end one excluded, or the end of the string
+pub fn subs(args: &[MalValue]) -> Result<MalValue> {
+    let (s, start, end) = match args {
+        [s, start] => (string("subs", s)?, number("subs", start)?, None),
+        [s, start, end] => (
+            string("subs", s)?,
+            number("subs", start)?,
+            Some(number("subs", end)?),
+        ),
+        _ => return Err(anyhow!("subs needs a string, a start and maybe an end")),
+    };
+    let start = char_offset(s, start)?;
+    let end = end.map_or(Ok(s.len()), |end| char_offset(s, end))?;
+    match s.get(start..end) {
+        Some(sub) => Ok(MalValue::String(sub.into())),
+        None => Err(anyhow!("subs needs a start before the end")),
+    }
+}
+
+/// Regex matching a string separator literally, or the regex itself
+fn pattern(name: &str, arg: &MalValue) -> Result<Rc<Regex>> {
+    match arg {
+        MalValue::Regex(re) => Ok(re.clone()),
+        MalValue::String(s) => Ok(Rc::new(Regex::new(&regex::escape(s))?)),
         a => Err(anyhow!(
-            "Cannot div args: {:?}",
-            a.iter().map(|v| pr_str(v)).collect::<Vec<_>>()
+            "{} needs a string or a regex, got: {}",
+            name,
+            pr_str(a)
         )),
     }
 }
 
-pub fn prn(args: &Vec<MalValue>) -> Result<MalValue> {
-    let str: String = args.iter().map(|v| pr_str(v)).join(" ");
-    println!("{}", str);
-    Ok(MalValue::Nil)
+/// Vector of the parts of the string between the matches of the separator, at most `limit`
+pub fn split(args: &[MalValue]) -> Result<MalValue> {
+    let (s, sep, limit) = match args {
+        [s, sep] => (s, sep, None),
+        [s, sep, limit] => (s, sep, Some(number("split", limit)?)),
+        _ => {
+            return Err(anyhow!(
+                "split needs a string, a separator and maybe a limit"
+            ))
+        }
+    };
+    let (s, sep) = (string("split", s)?, pattern("split", sep)?);
+    let part = |p: &str| MalValue::String(p.into());
+    let parts = match limit.map(usize::try_from) {
+        Some(Ok(limit)) if limit > 0 => sep.splitn(s, limit).map(part).collect(),
+        _ => sep.split(s).map(part).collect(),
+    };
+    Ok(MalValue::Vec(Rc::new(parts)))
 }
 
-pub fn list(args: &Vec<MalValue>) -> Result<MalValue> {
-    Ok(MalValue::List(Rc::new(args.clone())))
+/// Items of the collection printed like `str`, with the separator between them
+pub fn join(args: &[MalValue]) -> Result<MalValue> {
+    let (sep, coll) = match args {
+        [coll] => ("", coll),
+        [sep, coll] => (string("join", sep)?, coll),
+        _ => return Err(anyhow!("join needs maybe a separator and a collection")),
+    };
+    let items = sequence("join", coll)?;
+    Ok(MalValue::String(
+        items.iter().map(print_str).join(sep).into(),
+    ))
 }
 
-pub fn is_list(args: &Vec<MalValue>) -> Result<MalValue> {
-    match &args[..] {
-        [MalValue::List(_)] => Ok(MalValue::True),
-        [_] => Ok(MalValue::False),
+pub fn upper_case(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [s] => Ok(MalValue::String(
+            string("upper-case", s)?.to_uppercase().into(),
+        )),
+        _ => Err(anyhow!("upper-case needs a string")),
+    }
+}
+
+pub fn lower_case(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [s] => Ok(MalValue::String(
+            string("lower-case", s)?.to_lowercase().into(),
+        )),
+        _ => Err(anyhow!("lower-case needs a string")),
+    }
+}
+
+/// String without the whitespace at both ends
+pub fn trim(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [s] => Ok(MalValue::String(string("trim", s)?.trim().into())),
+        _ => Err(anyhow!("trim needs a string")),
+    }
+}
+
+/// The two string arguments of `name`
+fn two_strings<'a>(name: &str, args: &'a [MalValue]) -> Result<(&'a str, &'a str)> {
+    match args {
+        [a, b] => Ok((string(name, a)?, string(name, b)?)),
+        _ => Err(anyhow!("{} needs two strings", name)),
+    }
+}
+
+pub fn starts_with(args: &[MalValue]) -> Result<MalValue> {
+    let (s, prefix) = two_strings("starts-with?", args)?;
+    Ok(s.starts_with(prefix).into())
+}
+
+pub fn ends_with(args: &[MalValue]) -> Result<MalValue> {
+    let (s, suffix) = two_strings("ends-with?", args)?;
+    Ok(s.ends_with(suffix).into())
+}
+
+pub fn includes(args: &[MalValue]) -> Result<MalValue> {
+    let (s, sub) = two_strings("includes?", args)?;
+    Ok(s.contains(sub).into())
+}
+
+/// Replacement for [Regex::replace_all] with `$1b` being the first group followed by `b`, the
+/// regex crate reading it as the group named `1b`. Named groups are written `${name}`.
+fn replacement(to: &str) -> String {
+    let mut out = String::with_capacity(to.len());
+    let mut chars = to.chars().peekable();
+    while let Some(c) = chars.next() {
+        out.push(c);
+        match (c, chars.peek()) {
+            // An escaped `$`
+            ('$', Some('$')) => out.extend(chars.next()),
+            ('$', Some(d)) if d.is_ascii_digit() => {
+                out.push('{');
+                while let Some(d) = chars.next_if(char::is_ascii_digit) {
+                    out.push(d);
+                }
+                out.push('}');
+            }
+            _ => (),
+        }
+    }
+    out
+}
+
+/// String with every match of the string or regex replaced, `$1` or `${1}` in the replacement
+/// being the first group of a regex, `${name}` a named group and `$$` a `$`
+pub fn replace(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [s, MalValue::String(from), to] => {
+            let (s, to) = (string("replace", s)?, string("replace", to)?);
+            Ok(MalValue::String(s.replace(&**from, to).into()))
+        }
+        [s, MalValue::Regex(re), to] => {
+            let (s, to) = (string("replace", s)?, string("replace", to)?);
+            Ok(MalValue::String(re.replace_all(s, replacement(to)).into()))
+        }
+        _ => Err(anyhow!("replace needs a string, a match and a replacement")),
+    }
+}
+
+/// Index of the first character of the substring, from the start index, nil if it is not found
+pub fn index_of(args: &[MalValue]) -> Result<MalValue> {
+    let (s, sub, from) = match args {
+        [s, sub] => (s, sub, 0),
+        [s, sub, from] => (s, sub, number("index-of", from)?),
+        _ => {
+            return Err(anyhow!(
+                "index-of needs a string, a substring and maybe a start"
+            ))
+        }
+    };
+    let (s, sub) = (string("index-of", s)?, string("index-of", sub)?);
+    // Nothing is found past the end, not even the empty string
+    let Ok(start) = char_offset(s, from.max(0)) else {
+        return Ok(MalValue::Nil);
+    };
+    Ok(match s[start..].find(sub) {
+        Some(i) => {
+            MalValue::Number((from.max(0) as usize + s[start..start + i].chars().count()) as i64)
+        }
+        None => MalValue::Nil,
+    })
+}
+
+/// String of the template with `%s` replaced by the next argument printed like `str`, `%d` by
+/// the next number and `%%` by `%`. A width between `%` and the directive pads the argument on
+/// the left with spaces, on the right when it starts with `-`, and with zeros for `%0Nd`.
+pub fn format(args: &[MalValue]) -> Result<MalValue> {
+    let (template, mut vals) = match args {
+        [template, vals @ ..] => (string("format", template)?, vals.iter()),
+        [] => return Err(anyhow!("format needs a template")),
+    };
+    let mut out = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        let mut next = || {
+            vals.next()
+                .ok_or_else(|| anyhow!("Not enough arguments to format"))
+        };
+        let flag = chars.next_if(|c| *c == '-' || *c == '0');
+        let mut digits = String::new();
+        while let Some(d) = chars.next_if(char::is_ascii_digit) {
+            digits.push(d);
+        }
+        let width = match digits.as_str() {
+            "" => 0,
+            w => w.parse().context("Format width too large")?,
+        };
+        budget::check_size(width)?;
+        let arg = match (flag, width, chars.next()) {
+            (None, 0, Some('%')) => {
+                out.push('%');
+                continue;
+            }
+            (None | Some('-'), _, Some('s')) => print_str(next()?),
+            (_, _, Some('d')) => number("format %d", next()?)?.to_string(),
+            (_, _, Some(d)) => {
+                let flag = flag.map(String::from).unwrap_or_default();
+                return Err(anyhow!("Unknown format directive %{}{}{}", flag, digits, d));
+            }
+            (_, _, None) => return Err(anyhow!("Format template ending with %")),
+        };
+        let pad = width.saturating_sub(arg.chars().count());
+        match flag {
+            Some('-') => {
+                out.push_str(&arg);
+                out.extend(std::iter::repeat_n(' ', pad));
+            }
+            Some(_) => {
+                // The zeros go after the sign
+                let digits = arg.strip_prefix('-');
+                out.push_str(if digits.is_some() { "-" } else { "" });
+                out.extend(std::iter::repeat_n('0', pad));
+                out.push_str(digits.unwrap_or(&arg));
+            }
+            None => {
+                out.extend(std::iter::repeat_n(' ', pad));
+                out.push_str(&arg);
+            }
+        }
+    }
+    Ok(MalValue::String(out.into()))
+}
+
+/// Regex of the pattern
+pub fn re_pattern(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [MalValue::Regex(re)] => Ok(MalValue::Regex(re.clone())),
+        [MalValue::String(s)] => Ok(MalValue::Regex(Rc::new(
+            Regex::new(s).with_context(|| anyhow!("Invalid regex #\"{}\"", s))?,
+        ))),
+        _ => Err(anyhow!("re-pattern needs a string")),
+    }
+}
+
+/// The regex and the string arguments of `name`
+fn regex_and_string<'a>(name: &str, args: &'a [MalValue]) -> Result<(&'a Regex, &'a str)> {
+    match args {
+        [MalValue::Regex(re), s] => Ok((re, string(name, s)?)),
+        _ => Err(anyhow!("{} needs a regex and a string", name)),
+    }
+}
+
+/// The matched string, or a vector of it followed by the groups, nil for the ones not matched
+fn match_value(caps: &regex::Captures) -> MalValue {
+    let group =
+        |m: Option<regex::Match>| m.map_or(MalValue::Nil, |m| MalValue::String(m.as_str().into()));
+    match caps.len() {
+        1 => group(caps.get(0)),
+        _ => MalValue::Vec(Rc::new(caps.iter().map(group).collect())),
+    }
+}
+
+/// First match of the regex in the string, see [match_value], nil if there is none
+pub fn re_find(args: &[MalValue]) -> Result<MalValue> {
+    let (re, s) = regex_and_string("re-find", args)?;
+    Ok(re.captures(s).map_or(MalValue::Nil, |c| match_value(&c)))
+}
+
+/// Match of the regex on the whole string, see [match_value], nil if it doesn't match
+pub fn re_matches(args: &[MalValue]) -> Result<MalValue> {
+    let (re, s) = regex_and_string("re-matches", args)?;
+    let whole = Regex::new(&format!("^(?:{})$", re.as_str()))?;
+    Ok(whole.captures(s).map_or(MalValue::Nil, |c| match_value(&c)))
+}
+
+/// List of the successive matches of the regex in the string, nil if there is none
+pub fn re_seq(args: &[MalValue]) -> Result<MalValue> {
+    let (re, s) = regex_and_string("re-seq", args)?;
+    let matches: Vec<_> = re.captures_iter(s).map(|c| match_value(&c)).collect();
+    match matches.is_empty() {
+        true => Ok(MalValue::Nil),
+        false => Ok(MalValue::List(Rc::new(matches))),
+    }
+}
+
+/// String with every match of the regex replaced, the replacement written like for `replace`
+pub fn re_replace(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [MalValue::Regex(re), s, to] => {
+            let (s, to) = (string("re-replace", s)?, string("re-replace", to)?);
+            Ok(MalValue::String(re.replace_all(s, replacement(to)).into()))
+        }
         _ => Err(anyhow!(
-            "Invalid number of args for list?, got {}",
-            args.len()
+            "re-replace needs a regex, a string and a replacement"
+        )),
+    }
+}
+
+/// Whether the single argument of `name` satisfies the predicate
+fn is(name: &str, args: &[MalValue], pred: fn(&MalValue) -> bool) -> Result<MalValue> {
+    match args {
+        [val] => Ok(pred(val).into()),
+        _ => Err(anyhow!("{} needs one argument, got {}", name, args.len())),
+    }
+}
+
+pub fn is_nil(args: &[MalValue]) -> Result<MalValue> {
+    is("nil?", args, |v| matches!(v, MalValue::Nil))
+}
+
+pub fn is_true(args: &[MalValue]) -> Result<MalValue> {
+    is("true?", args, |v| matches!(v, MalValue::True))
+}
+
+pub fn is_false(args: &[MalValue]) -> Result<MalValue> {
+    is("false?", args, |v| matches!(v, MalValue::False))
+}
+
+pub fn is_number(args: &[MalValue]) -> Result<MalValue> {
+    is("number?", args, |v| matches!(v, MalValue::Number(_)))
+}
+
+pub fn is_string(args: &[MalValue]) -> Result<MalValue> {
+    is("string?", args, |v| matches!(v, MalValue::String(_)))
+}
+
+pub fn is_symbol(args: &[MalValue]) -> Result<MalValue> {
+    is("symbol?", args, |v| matches!(v, MalValue::Sym(_)))
+}
+
+pub fn is_keyword(args: &[MalValue]) -> Result<MalValue> {
+    is("keyword?", args, |v| matches!(v, MalValue::Atom(_)))
+}
+
+pub fn is_vector(args: &[MalValue]) -> Result<MalValue> {
+    is("vector?", args, |v| matches!(v, MalValue::Vec(_)))
+}
+
+/// Whether the value is a map or a sorted map
+pub fn is_map(args: &[MalValue]) -> Result<MalValue> {
+    is("map?", args, |v| v.map_entries().is_some())
+}
+
+/// Whether the value is a list or a vector
+pub fn is_sequential(args: &[MalValue]) -> Result<MalValue> {
+    is("sequential?", args, |v| {
+        matches!(v, MalValue::List(_) | MalValue::Vec(_))
+    })
+}
+
+/// Whether the value is a builtin or a function defined in mal, whatever the engine
+pub fn is_fn(args: &[MalValue]) -> Result<MalValue> {
+    is("fn?", args, |v| {
+        matches!(
+            v,
+            MalValue::Function(_)
+                | MalValue::Closure(_)
+                | MalValue::Compiled(_)
+                | MalValue::Code(_)
+        )
+    })
+}
+
+/// Always false, the interpreter has no macros
+pub fn is_macro(args: &[MalValue]) -> Result<MalValue> {
+    is("macro?", args, |_| false)
+}
+
+/// Always false, the interpreter has no mutable atoms, `:kw` keywords are `keyword?`
+pub fn is_atom(args: &[MalValue]) -> Result<MalValue> {
+    is("atom?", args, |_| false)
+}
+
+/// Name for `symbol` or `keyword` that the reader reads back, not empty and without whitespace
+/// or delimiters
+fn readable_name<'a>(name: &str, arg: &'a MalValue) -> Result<&'a str> {
+    let s = string(name, arg)?;
+    let delimiter = |c: char| c.is_whitespace() || "[]{}()'\"`,;".contains(c);
+    match s.is_empty() || s.contains(delimiter) {
+        true => Err(anyhow!("Invalid {} name: {}", name, pr_str(arg))),
+        false => Ok(s),
+    }
+}
+
+/// Symbol of the name
+pub fn symbol(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [MalValue::Sym(sym)] => Ok(MalValue::Sym(*sym)),
+        [name] => {
+            let name = readable_name("symbol", name)?;
+            Ok(MalValue::Sym(Symbol::try_new(name)?))
+        }
+        _ => Err(anyhow!("symbol needs a name")),
+    }
+}
+
+/// Keyword of the name, or of the name of a symbol
+pub fn keyword(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [MalValue::Atom(kw) | MalValue::Sym(kw)] => Ok(MalValue::Atom(*kw)),
+        [name] => {
+            let name = readable_name("keyword", name)?;
+            Ok(MalValue::Atom(Symbol::try_new(name)?))
+        }
+        _ => Err(anyhow!("keyword needs a name")),
+    }
+}
+
+/// Name of a keyword or a symbol, without its namespace, or the string itself
+pub fn name(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [MalValue::Atom(sym) | MalValue::Sym(sym)] => {
+            let name = sym.qualified().map_or(*sym, |(_, name)| name);
+            Ok(MalValue::String(name.name().into()))
+        }
+        [MalValue::String(s)] => Ok(MalValue::String(s.clone())),
+        a => Err(anyhow!(
+            "name needs a keyword, a symbol or a string: {:?}",
+            a.iter().map(pr_str).collect::<Vec<_>>()
         )),
     }
 }
 
-pub fn is_empty(args: &Vec<MalValue>) -> Result<MalValue> {
-    match &args[..] {
-        [MalValue::List(l)] => Ok(l.is_empty().into()),
+/// Number written in the string, in base 10
+pub fn str_to_int(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [s] => {
+            let s = string("str->int", s)?;
+            let n = s
+                .trim()
+                .parse()
+                .with_context(|| anyhow!("Cannot read an integer from \"{}\"", s))?;
+            Ok(MalValue::Number(n))
+        }
+        _ => Err(anyhow!("str->int needs a string")),
+    }
+}
+
+/// Number written in base 10
+pub fn int_to_str(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [n] => Ok(MalValue::String(number("int->str", n)?.to_string().into())),
+        _ => Err(anyhow!("int->str needs a number")),
+    }
+}
+
+/// Vector of the arguments
+pub fn vector(args: &[MalValue]) -> Result<MalValue> {
+    Ok(MalValue::Vec(Rc::new(args.to_vec())))
+}
+
+/// Whether all the arguments are equal
+pub fn eq(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [] => Err(anyhow!("= needs at least one argument")),
+        a => Ok(a.windows(2).all(|w| w[0] == w[1]).into()),
+    }
+}
+
+/// -1, 0 or 1 as the first argument comes before, is equal to or comes after the second one
+pub fn compare(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [l, r] => {
+            check_comparable(l)?;
+            check_comparable(r)?;
+            Ok(MalValue::Number(l.cmp(r) as i64))
+        }
         a => Err(anyhow!(
-            "Cannot check is empty, too many args or not a list: {:?}",
-            a.iter().map(|v| pr_str(v)).collect::<Vec<_>>()
+            "compare needs two arguments: {:?}",
+            a.iter().map(pr_str).collect::<Vec<_>>()
         )),
     }
 }
 
-pub fn count(args: &Vec<MalValue>) -> Result<MalValue> {
-    match &args[..] {
-        [MalValue::List(l)] => Ok(MalValue::Number(l.len() as i64)),
-        _a => Ok(MalValue::Number(0)),
+/// Whether each pair of consecutive numbers is in the order
+fn compare_numbers(
+    name: &str,
+    args: &[MalValue],
+    ordered: fn(Ordering) -> bool,
+) -> Result<MalValue> {
+    let (first, rest) = args
+        .split_first()
+        .ok_or_else(|| anyhow!("{} needs at least one argument", name))?;
+    let mut prev = number(name, first)?;
+    let mut all = true;
+    for arg in rest {
+        let n = number(name, arg)?;
+        all &= ordered(prev.cmp(&n));
+        prev = n;
     }
+    Ok(all.into())
 }
-pub fn eq(args: &Vec<MalValue>) -> Result<MalValue> {
-    match &args[..] {
-        [l, r] => Ok((l == r).into()),
+
+pub fn lt(args: &[MalValue]) -> Result<MalValue> {
+    compare_numbers("<", args, Ordering::is_lt)
+}
+
+pub fn lt_eq(args: &[MalValue]) -> Result<MalValue> {
+    compare_numbers("<=", args, Ordering::is_le)
+}
+
+pub fn gt(args: &[MalValue]) -> Result<MalValue> {
+    compare_numbers(">", args, Ordering::is_gt)
+}
+
+pub fn gt_eq(args: &[MalValue]) -> Result<MalValue> {
+    compare_numbers(">=", args, Ordering::is_ge)
+}
+
+/// Items of a collection, sorted by `compare` or the comparator
+pub fn sort(args: &[MalValue]) -> Result<MalValue> {
+    let (by, coll) = match args {
+        [coll] => (None, coll),
+        [by, coll] => (Some(by), coll),
+        a => {
+            return Err(anyhow!(
+                "sort needs a collection and maybe a comparator: {:?}",
+                a.iter().map(pr_str).collect::<Vec<_>>()
+            ))
+        }
+    };
+    let items = sequence("sort", coll)?.to_vec();
+    sort_on(items.clone(), items, by)
+}
+
+/// Items of a collection, sorted by the values of `keyfn` on them, with `compare` or
+/// the comparator
+pub fn sort_by(args: &[MalValue]) -> Result<MalValue> {
+    let (keyfn, by, coll) = match args {
+        [keyfn, coll] => (keyfn, None, coll),
+        [keyfn, by, coll] => (keyfn, Some(by), coll),
+        a => {
+            return Err(anyhow!(
+                "sort-by needs a key function, maybe a comparator and a collection: {:?}",
+                a.iter().map(pr_str).collect::<Vec<_>>()
+            ))
+        }
+    };
+    let items = sequence("sort-by", coll)?.to_vec();
+    let keys = items
+        .iter()
+        .map(|i| keyfn.apply(Rc::new(vec![i.clone()])))
+        .collect::<Result<_>>()?;
+    sort_on(items, keys, by)
+}
+
+/// List of the items in the order of their keys, stable
+fn sort_on(items: Vec<MalValue>, keys: Vec<MalValue>, by: Option<&MalValue>) -> Result<MalValue> {
+    if by.is_none() {
+        keys.iter().try_for_each(check_comparable)?;
+    }
+    let pairs = keys.into_iter().zip(items).collect();
+    let sorted = merge_sort_by(pairs, &mut |(k1, _), (k2, _)| match by {
+        None => Ok(k1.cmp(k2)),
+        Some(by) => compare_with(by, k1, k2),
+    })?;
+    Ok(MalValue::List(Rc::new(
+        sorted.into_iter().map(|(_, i)| i).collect(),
+    )))
+}
+
+pub fn load_file(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [MalValue::String(_)] if host::is_deterministic() => {
+            Err(anyhow!("load-file is disabled in deterministic mode"))
+        }
+        [MalValue::String(path)] => malc::load(Path::new(&**path)),
         a => Err(anyhow!(
-            "Cannot equal args when more than 2: {:?}",
-            a.iter().map(|v| pr_str(v)).collect::<Vec<_>>()
+            "load-file needs a path: {:?}",
+            a.iter().map(pr_str).collect::<Vec<_>>()
         )),
     }
 }
 
-pub fn lt_eq(args: &Vec<MalValue>) -> Result<MalValue> {
-    match &args[..] {
-        [MalValue::Number(l), MalValue::Number(r)] => Ok((l <= r).into()),
+/// Prints the optimised forms of the source, as evaluated in the current namespace
+pub fn optimize(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [MalValue::String(source)] => {
+            let env = namespace::current().env.clone();
+            // The source is a string of the program, possibly built at runtime
+            for ast in reader::read_untrusted(source)? {
+                host::println(&pr_str(&optimizer::optimize(&env, &ast)));
+            }
+            Ok(MalValue::Nil)
+        }
         a => Err(anyhow!(
-            "Cannot lt_eq than on: {:?}",
-            a.iter().map(|v| pr_str(v)).collect::<Vec<_>>()
+            "optimize needs the source of a form: {:?}",
+            a.iter().map(pr_str).collect::<Vec<_>>()
         )),
     }
 }
-pub fn lt(args: &Vec<MalValue>) -> Result<MalValue> {
-    match &args[..] {
-        [MalValue::Number(l), MalValue::Number(r)] => Ok((l < r).into()),
+
+/// Breaks the garbage cycles of environments and closures, returns how many objects were cleared
+pub fn gc(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [] => Ok(MalValue::Number(gc::collect() as i64)),
         a => Err(anyhow!(
-            "Cannot lt than on: {:?}",
-            a.iter().map(|v| pr_str(v)).collect::<Vec<_>>()
+            "gc takes no arguments: {:?}",
+            a.iter().map(pr_str).collect::<Vec<_>>()
         )),
     }
 }
-pub fn gt(args: &Vec<MalValue>) -> Result<MalValue> {
-    match &args[..] {
-        [MalValue::Number(l), MalValue::Number(r)] => Ok((l > r).into()),
+
+/// Map of the live environments, the tracked objects and the collections run so far
+pub fn heap_stats(args: &[MalValue]) -> Result<MalValue> {
+    if !args.is_empty() {
+        return Err(anyhow!(
+            "heap-stats takes no arguments: {:?}",
+            args.iter().map(pr_str).collect::<Vec<_>>()
+        ));
+    }
+    let stats = gc::stats();
+    let map = [
+        (":envs", stats.envs),
+        (":tracked", stats.tracked),
+        (":collections", stats.collections),
+        (":collected", stats.collected),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_string(), MalValue::Number(v as i64)))
+    .collect();
+    Ok(MalValue::Map(Rc::new(map)))
+}
+
+/// Random integer from 0 up to, but not including, the bound
+pub fn rand_int(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [MalValue::Number(n)] if *n > 0 => {
+            Ok(MalValue::Number((host::random() % *n as u64) as i64))
+        }
         a => Err(anyhow!(
-            "Cannot gt than on: {:?}",
-            a.iter().map(|v| pr_str(v)).collect::<Vec<_>>()
+            "rand-int needs a positive bound: {:?}",
+            a.iter().map(pr_str).collect::<Vec<_>>()
         )),
     }
 }
-pub fn gt_eq(args: &Vec<MalValue>) -> Result<MalValue> {
-    match &args[..] {
-        [MalValue::Number(l), MalValue::Number(r)] => Ok((l >= r).into()),
+
+/// Milliseconds since the Unix epoch, or of the virtual clock in deterministic mode
+pub fn time_ms(args: &[MalValue]) -> Result<MalValue> {
+    match args {
+        [] => Ok(MalValue::Number(host::now_ms())),
         a => Err(anyhow!(
-            "Cannot gt_eq than on: {:?}",
-            a.iter().map(|v| pr_str(v)).collect::<Vec<_>>()
+            "time-ms takes no arguments: {:?}",
+            a.iter().map(pr_str).collect::<Vec<_>>()
         )),
     }
 }
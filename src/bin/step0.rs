@@ -15,7 +15,7 @@ fn main() -> Result<()> {
             Ok(line) => {
                 rl.add_history_entry(&line)?;
                 rl.save_history(".mal-history")?;
-                if line.len() > 0 {
+                if !line.is_empty() {
                     println!("{}", line);
                 }
             }
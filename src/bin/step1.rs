@@ -16,7 +16,7 @@ fn main() -> Result<()> {
             Ok(line) => {
                 rl.add_history_entry(&line)?;
                 rl.save_history(".mal-history")?;
-                if line.len() > 0 {
+                if !line.is_empty() {
                     match reader::read_str(&line) {
                         Ok(mv) => {
                             println!("{}", printer::pr_str(&mv))
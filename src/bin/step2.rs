@@ -6,12 +6,13 @@ extern crate rustyline;
 use anyhow::{anyhow, Context};
 use mal_rust::printer::pr_str;
 use mal_rust::reader;
-use mal_rust::types::MalValue;
+use mal_rust::symbol::Symbol;
+use mal_rust::types::{MalMap, MalValue};
 use mal_rust::{base_fn, Result};
 use rustyline::error::ReadlineError;
 use rustyline::DefaultEditor;
 
-pub type Env = HashMap<String, MalValue>;
+pub type Env = HashMap<Symbol, MalValue>;
 
 fn eval_ast(env: &mut Env, ast: &MalValue) -> Result<MalValue> {
     match ast {
@@ -34,7 +35,7 @@ fn eval_ast(env: &mut Env, ast: &MalValue) -> Result<MalValue> {
                     let val = eval(env, v)?;
                     Ok((k.clone(), val))
                 })
-                .collect::<Result<HashMap<String, MalValue>>>()?;
+                .collect::<Result<MalMap>>()?;
             Ok(MalValue::Map(Rc::new(val)))
         }
         v => Ok(v.clone()),
@@ -69,10 +70,10 @@ fn main() -> Result<()> {
     }
 
     let mut env = Env::new();
-    env.insert("+".to_string(), MalValue::Function(base_fn::add));
-    env.insert("-".to_string(), MalValue::Function(base_fn::sub));
-    env.insert("*".to_string(), MalValue::Function(base_fn::mult));
-    env.insert("/".to_string(), MalValue::Function(base_fn::div));
+    env.insert("+".into(), MalValue::Function(base_fn::add));
+    env.insert("-".into(), MalValue::Function(base_fn::sub));
+    env.insert("*".into(), MalValue::Function(base_fn::mult));
+    env.insert("/".into(), MalValue::Function(base_fn::div));
 
     loop {
         let readline = rl.readline("mal-rs> ");
@@ -80,7 +81,7 @@ fn main() -> Result<()> {
             Ok(line) => {
                 rl.add_history_entry(&line)?;
                 rl.save_history(".mal-history")?;
-                if line.len() > 0 {
+                if !line.is_empty() {
                     let res = match reader::read_str(&line) {
                         Ok(ast) => eval(&mut env, &ast),
                         Err(e) => Err(e),
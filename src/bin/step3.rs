@@ -1,4 +1,3 @@
-use std::collections::HashMap;
 use std::rc::Rc;
 
 extern crate rustyline;
@@ -7,15 +6,15 @@ use anyhow::{anyhow, Context};
 use itertools::Itertools;
 use mal_rust::env::{env_get_sym, env_set, env_set_sym, Env};
 use mal_rust::printer::pr_str;
-use mal_rust::reader;
-use mal_rust::types::MalValue;
+use mal_rust::types::{MalMap, MalValue};
 use mal_rust::{base_fn, Result};
+use mal_rust::{reader, symbol};
 use rustyline::error::ReadlineError;
 use rustyline::DefaultEditor;
 
 fn eval_ast(env: &mut Rc<Env>, ast: &MalValue) -> Result<MalValue> {
     match ast {
-        MalValue::Sym(s) => env_get_sym(env, s).context(anyhow!("Symbol: '{}' not found", s)),
+        MalValue::Sym(s) => env_get_sym(env, *s).context(anyhow!("Symbol: '{}' not found", s)),
         MalValue::List(list) => {
             let val = list.iter().map(|v| eval(env, v)).collect::<Result<_>>()?;
             Ok(MalValue::List(Rc::new(val)))
@@ -31,7 +30,7 @@ fn eval_ast(env: &mut Rc<Env>, ast: &MalValue) -> Result<MalValue> {
                     let val = eval(env, v)?;
                     Ok((k.clone(), val))
                 })
-                .collect::<Result<HashMap<String, MalValue>>>()?;
+                .collect::<Result<MalMap>>()?;
             Ok(MalValue::Map(Rc::new(val)))
         }
         v => Ok(v.clone()),
@@ -47,8 +46,8 @@ fn eval(env: &mut Rc<Env>, ast: &MalValue) -> Result<MalValue> {
                 let head = list[0].clone();
                 let tail = &list[1..];
                 match head {
-                    MalValue::Sym(sym) => match sym.as_str() {
-                        "def!" => {
+                    MalValue::Sym(sym) => match sym {
+                        symbol::DEF => {
                             if tail.len() > 2 {
                                 return Err(anyhow!("Too many arguemnts to def"));
                             }
@@ -56,7 +55,7 @@ fn eval(env: &mut Rc<Env>, ast: &MalValue) -> Result<MalValue> {
                             env_set(env, &tail[0], val.clone())?;
                             Ok(val)
                         }
-                        "let*" => {
+                        symbol::LET => {
                             if tail.len() > 3 {
                                 return Err(anyhow!("Too many arguemnts to let"));
                             }
@@ -107,10 +106,10 @@ fn main() -> Result<()> {
     }
 
     let mut env = Rc::new(Env::new());
-    env_set_sym(&env, "+".to_string(), MalValue::Function(base_fn::add));
-    env_set_sym(&env, "-".to_string(), MalValue::Function(base_fn::sub));
-    env_set_sym(&env, "*".to_string(), MalValue::Function(base_fn::mult));
-    env_set_sym(&env, "/".to_string(), MalValue::Function(base_fn::div));
+    env_set_sym(&env, "+".into(), MalValue::Function(base_fn::add));
+    env_set_sym(&env, "-".into(), MalValue::Function(base_fn::sub));
+    env_set_sym(&env, "*".into(), MalValue::Function(base_fn::mult));
+    env_set_sym(&env, "/".into(), MalValue::Function(base_fn::div));
 
     loop {
         let readline = rl.readline("mal-rs> ");
@@ -118,7 +117,7 @@ fn main() -> Result<()> {
             Ok(line) => {
                 rl.add_history_entry(&line)?;
                 rl.save_history(".mal-history")?;
-                if line.len() > 0 {
+                if !line.is_empty() {
                     let res = match reader::read_str(&line) {
                         Ok(ast) => eval(&mut env, &ast),
                         Err(e) => Err(e),
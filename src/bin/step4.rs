@@ -1,21 +1,34 @@
-use std::collections::HashMap;
+use std::path::Path;
 use std::rc::Rc;
+use std::time::Duration;
 
 extern crate rustyline;
 
+// Counts the allocations for `--memory`
+#[global_allocator]
+static ALLOC: budget::CountingAlloc = budget::CountingAlloc;
+
 use anyhow::{anyhow, Context};
-use itertools::Itertools;
-use mal_rust::env::{env_get_sym, env_set, env_set_sym, Env};
+use mal_rust::destructure::destructure;
+use mal_rust::env::{
+    env_capture, env_get_local, env_get_sym, env_globals, env_set, env_set_local, env_set_sym, Env,
+};
 use mal_rust::printer::pr_str;
-use mal_rust::reader;
-use mal_rust::types::MalValue;
+use mal_rust::sandbox::{Sandbox, SANDBOX_NS};
+use mal_rust::types::{Closure, MalMap, MalValue};
 use mal_rust::{base_fn, Result};
+use mal_rust::{
+    budget, closure_compiler, host, malc, namespace, optimizer, reader, resolver, symbol, vm,
+};
 use rustyline::error::ReadlineError;
 use rustyline::DefaultEditor;
 
 fn eval_ast(env: &mut Rc<Env>, ast: &MalValue) -> Result<MalValue> {
     match ast {
-        MalValue::Sym(s) => env_get_sym(env, s).context(anyhow!("Symbol: '{}' not found", s)),
+        MalValue::Sym(s) => env_get_sym(env, *s).context(anyhow!("Symbol: '{}' not found", s)),
+        MalValue::Local { sym, depth, slot } => {
+            env_get_local(env, *depth, *slot).context(anyhow!("Local: '{}' not found", sym))
+        }
         MalValue::List(list) => {
             let val = list.iter().map(|v| eval(env, v)).collect::<Result<_>>()?;
             Ok(MalValue::List(Rc::new(val)))
@@ -31,7 +44,7 @@ fn eval_ast(env: &mut Rc<Env>, ast: &MalValue) -> Result<MalValue> {
                     let val = eval(env, v)?;
                     Ok((k.clone(), val))
                 })
-                .collect::<Result<HashMap<String, MalValue>>>()?;
+                .collect::<Result<MalMap>>()?;
             Ok(MalValue::Map(Rc::new(val)))
         }
         v => Ok(v.clone()),
@@ -44,151 +57,235 @@ fn eval(env: &mut Rc<Env>, ast: &MalValue) -> Result<MalValue> {
             if list.is_empty() {
                 Ok(ast.clone())
             } else {
-                let head = list[0].clone();
                 let tail = &list[1..];
-                match head {
-                    MalValue::Sym(sym) => match sym.as_str() {
-                        "def!" => {
+                match &list[0] {
+                    MalValue::Sym(sym) => match *sym {
+                        symbol::DEF => {
                             if tail.len() > 2 {
                                 return Err(anyhow!("Too many arguemnts to def"));
                             }
+                            // Definitions are global, even inside a function
                             let val = eval(env, &tail[1])?;
+                            env_set(env_globals(env), &tail[0], val.clone())?;
+                            Ok(val)
+                        }
+                        symbol::DEF_PRIVATE => {
+                            if tail.len() != 2 {
+                                return Err(anyhow!("Invalid number of arguments to def-"));
+                            }
+                            let val = eval(env, &tail[1])?;
+                            let env = env_globals(env);
                             env_set(env, &tail[0], val.clone())?;
+                            namespace::set_private(env, &tail[0]);
                             Ok(val)
                         }
-                        "let*" => {
-                            if tail.len() > 3 {
-                                return Err(anyhow!("Too many arguemnts to let"));
+                        symbol::NS => {
+                            *env = namespace::ns_form(tail)?.env.clone();
+                            Ok(MalValue::Nil)
+                        }
+                        symbol::IN_NS => match tail {
+                            [MalValue::Sym(name)] => {
+                                *env = namespace::in_ns(*name).env.clone();
+                                Ok(MalValue::Nil)
+                            }
+                            _ => Err(anyhow!("in-ns needs a namespace name")),
+                        },
+                        symbol::LET => {
+                            if tail.len() != 2 {
+                                return Err(anyhow!("Invalid number of arguments to let"));
                             }
-                            let mut new_env = let_binding(env.clone(), &tail[0])?;
-                            eval(&mut new_env, &tail[1])
+                            eval(env, &resolver::resolve_let(&tail[0], &tail[1])?)
                         }
-                        "do" => {
-                            let res: Result<Vec<_>> =
-                                tail.iter().map(|v| eval_ast(env, v)).collect();
-                            let vals = res?;
-                            let last = vals.last().cloned().unwrap_or(MalValue::Nil);
+                        symbol::DO => {
+                            let mut last = MalValue::Nil;
+                            for v in tail {
+                                last = eval(env, v)?;
+                            }
                             Ok(last)
                         }
-                        "if" => {
+                        symbol::IF => {
                             if tail.len() > 3 || tail.len() < 2 {
                                 return Err(anyhow!("Invalid number of arguemnts to if"));
                             }
                             let res = eval(env, &tail[0])?;
                             if res == MalValue::False || res == MalValue::Nil {
                                 let val = tail.get(2).unwrap_or(&MalValue::Nil);
-                                eval(env, &val)
+                                eval(env, val)
                             } else {
                                 eval(env, &tail[1])
                             }
                         }
-                        "fn*" => {
-                            if tail.len() != 2 {
-                                return Err(anyhow!("Invalid number of arguments for fn"));
-                            }
-                            let env = Rc::new(Env::new_child(env.clone()));
-                            let ast = Rc::new(tail[0].clone());
-                            let params = Rc::new(tail[1].clone());
-
-                            Ok(MalValue::Closure {
-                                func: eval,
-                                env,
-                                ast,
-                                params,
-                            })
-                        }
-                        _ => match eval_ast(env, ast)? {
-                            MalValue::List(l) | MalValue::Vec(l) => {
-                                l[0].apply(Rc::new(l[1..].to_vec()))
-                            }
-                            _ => Err(anyhow!("Didn't receive list after evaluating list")),
-                        },
-                    },
-                    _ => match eval_ast(env, ast)? {
-                        MalValue::List(l) => {
-                            let ref f = l[0].clone();
-                            f.apply(Rc::new(l[1..].to_vec()))
+                        symbol::FN => eval(env, &resolver::resolve_fn(tail)?),
+                        symbol::DEFN | symbol::DEFN_PRIVATE => {
+                            eval(env, &resolver::expand_defn(list)?)
                         }
-                        _ => Err(anyhow!("Need to evaluate a list")),
+                        _ => eval_call(env, list),
                     },
+                    _ => eval_call(env, list),
                 }
             }
         }
+        MalValue::Lambda(l) => Ok(MalValue::Closure(Rc::new(Closure {
+            func: eval,
+            env: env_capture(env, l)?,
+            name: l.name,
+            arities: l.arities.clone(),
+            source: l.source.clone(),
+        }))),
+        MalValue::Let(l) => {
+            let mut new_env = let_binding(env, &l.bindings, l.slots)?;
+            eval(&mut new_env, &l.body)
+        }
         v => eval_ast(env, v),
     }
 }
 
-fn let_binding(env: Rc<Env>, bindings: &MalValue) -> Result<Rc<Env>> {
-    match bindings {
-        MalValue::List(bindings) | MalValue::Vec(bindings) => {
-            let mut new_env = Rc::new(Env::new_child(env));
-            if bindings.len() % 2 != 0 {
-                return Err(anyhow!(
-                    "Invalid let  bindings, needs to have a key, value pair"
-                ));
-            }
-            for (k, v) in bindings.iter().tuples() {
-                let val = eval(&mut new_env, v)?;
-                env_set(&new_env, k, val)?;
-            }
-            Ok(new_env)
+fn eval_call(env: &mut Rc<Env>, list: &[MalValue]) -> Result<MalValue> {
+    budget::tick()?;
+    let _depth = budget::enter()?;
+    let f = eval(env, &list[0])?;
+    let args = list[1..]
+        .iter()
+        .map(|v| eval(env, v))
+        .collect::<Result<Vec<_>>>()?;
+    match f {
+        MalValue::Function(f) => {
+            let val = f(&args)?;
+            budget::check_len(&val)?;
+            Ok(val)
+        }
+        f => f.apply(Rc::new(args)),
+    }
+}
+
+fn let_binding(env: &Rc<Env>, bindings: &[(MalValue, MalValue)], slots: usize) -> Result<Rc<Env>> {
+    let mut new_env = Rc::new(Env::new_frame(env.clone(), vec![MalValue::Nil; slots]));
+    let mut slot = 0;
+    for (k, v) in bindings.iter() {
+        let val = eval(&mut new_env, v)?;
+        let mut vals = vec![];
+        destructure(k, val, &mut vals)
+            .with_context(|| anyhow!("Cannot bind '{}' in let", pr_str(k)))?;
+        for val in vals {
+            env_set_local(&new_env, slot, val)?;
+            slot += 1;
         }
-        v => Err(anyhow!(
-            "Let bindings needs a list, obtained: {}",
-            pr_str(v)
-        )),
     }
+    Ok(new_env)
 }
 
 fn main() -> Result<()> {
+    // Deep recursion stops with a stack overflow error instead of aborting
+    std::thread::Builder::new()
+        .stack_size(budget::STACK_SIZE)
+        .spawn(repl)?
+        .join()
+        .map_err(|_| anyhow!("The REPL panicked"))?
+}
+
+fn repl() -> Result<()> {
+    // `--vm` runs the forms on the bytecode VM, `--closures` compiles them to Rust closures,
+    // instead of the tree walking eval. `--optimize` rewrites the forms before evaluating them.
+    // `--fuel=N` and `--timeout=MS` limit the function calls and the duration of each evaluation,
+    // `--max-len=N` the collections returned by the builtins and `--memory=BYTES` the allocations.
+    // `--sandbox` evaluates the lines in a sandbox with the builtins not needing any I/O.
+    // `--deterministic[=SEED]` seeds the random numbers, uses a virtual clock and disables
+    // `load-file`, so that the same input always gives the same output.
+    // A file argument is run with the selected engine instead of starting the REPL, from its
+    // `.malc` cache with `--vm`, and so are the files of `load-file`.
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let engine = args
+        .iter()
+        .find(|a| *a == "--vm" || *a == "--closures")
+        .cloned();
+    let optimize = args.iter().any(|a| a == "--optimize");
+    let file = args.iter().find(|a| !a.starts_with("--"));
+    let limit = |flag: &str| -> Result<Option<u64>> {
+        args.iter()
+            .find_map(|a| a.strip_prefix(flag))
+            .map(|n| n.parse().with_context(|| anyhow!("Invalid {}{}", flag, n)))
+            .transpose()
+    };
+    let budget = budget::Budget {
+        fuel: limit("--fuel=")?,
+        timeout: limit("--timeout=")?.map(Duration::from_millis),
+        max_len: limit("--max-len=")?.map(|n| n as usize),
+        memory: limit("--memory=")?.map(|n| n as usize),
+    };
+    let sandbox = args
+        .iter()
+        .any(|a| a == "--sandbox")
+        .then(|| Sandbox::builder().allow_pure().budget(budget).build())
+        .transpose()?;
+    if args.iter().any(|a| a == "--deterministic") {
+        host::set_deterministic(0);
+    } else if let Some(seed) = limit("--deterministic=")? {
+        host::set_deterministic(seed);
+    }
+
+    let env = Rc::new(Env::new());
+    for builtin in base_fn::BUILTINS {
+        env_set_sym(&env, builtin.name.into(), MalValue::Function(builtin.func));
+    }
+    namespace::init(env);
+
+    // The cache holds the bytecode of the forms as written, files run from it only with `--vm`
+    let cached = engine.as_deref() == Some("--vm") && !optimize;
+    // Evaluates a form in the current namespace with the selected engine
+    let eval_form: malc::Evaluator = Rc::new(move |ast: &MalValue| {
+        let mut env = namespace::current().env.clone();
+        let ast = match optimize {
+            true => optimizer::optimize(&env, ast),
+            false => ast.clone(),
+        };
+        match engine.as_deref().unwrap_or_default() {
+            "--vm" => vm::eval(&env, &ast),
+            "--closures" => closure_compiler::eval(&env, &ast),
+            _ => eval(&mut env, &ast),
+        }
+    });
+    if !cached {
+        malc::set_evaluator(eval_form.clone());
+    }
+
+    if let Some(file) = file {
+        let res = budget::run(budget, || malc::load(Path::new(file)));
+        print!("{}", host::take_output());
+        return res.map(|_| ());
+    }
+
+    // Ctrl-C while evaluating stops the form, the definitions stay
+    budget::interrupt_on_sigint()?;
     let mut rl = DefaultEditor::new()?;
     if rl.load_history(".mal-history").is_err() {
         eprintln!("No previous history.");
     }
 
-    let mut env = Rc::new(Env::new());
-    env_set_sym(&env, "+".to_string(), MalValue::Function(base_fn::add));
-    env_set_sym(&env, "-".to_string(), MalValue::Function(base_fn::sub));
-    env_set_sym(&env, "*".to_string(), MalValue::Function(base_fn::mult));
-    env_set_sym(&env, "/".to_string(), MalValue::Function(base_fn::div));
-    env_set_sym(&env, "prn".to_string(), MalValue::Function(base_fn::prn));
-    env_set_sym(&env, "list".to_string(), MalValue::Function(base_fn::list));
-    env_set_sym(
-        &env,
-        "list?".to_string(),
-        MalValue::Function(base_fn::is_list),
-    );
-    env_set_sym(
-        &env,
-        "empty?".to_string(),
-        MalValue::Function(base_fn::is_empty),
-    );
-    env_set_sym(
-        &env,
-        "count".to_string(),
-        MalValue::Function(base_fn::count),
-    );
-    env_set_sym(&env, "=".to_string(), MalValue::Function(base_fn::eq));
-    env_set_sym(&env, "<".to_string(), MalValue::Function(base_fn::lt));
-    env_set_sym(&env, "<=".to_string(), MalValue::Function(base_fn::lt_eq));
-    env_set_sym(&env, ">".to_string(), MalValue::Function(base_fn::gt));
-    env_set_sym(&env, ">=".to_string(), MalValue::Function(base_fn::gt_eq));
-
     loop {
-        let readline = rl.readline("mal-rs> ");
+        let ns = match sandbox {
+            Some(_) => SANDBOX_NS.to_string(),
+            None => namespace::current().name.to_string(),
+        };
+        let readline = rl.readline(&format!("mal-rs {}> ", ns));
         match readline {
             Ok(line) => {
                 rl.add_history_entry(&line)?;
                 rl.save_history(".mal-history")?;
-                if line.len() > 0 {
-                    let res = match reader::read_str(&line) {
-                        Ok(ast) => eval(&mut env, &ast),
-                        Err(e) => Err(e),
-                    };
+                if let (Some(sandbox), false) = (&sandbox, line.is_empty()) {
+                    let res = sandbox.eval(&line);
+                    print!("{}", host::take_output());
+                    match res {
+                        Ok(v) => println!("{}", pr_str(&v)),
+                        Err(e) => println!("Error: {:#}", e),
+                    }
+                } else if !line.is_empty() {
+                    let res = reader::read_str(&line)
+                        .and_then(|ast| budget::run(budget, || eval_form(&ast)));
 
+                    print!("{}", host::take_output());
                     match res {
                         Ok(v) => println!("{}", pr_str(&v)),
-                        Err(e) => println!("Error: {}", e),
+                        Err(e) => println!("Error: {:#}", e),
                     }
                 }
             }
@@ -0,0 +1,287 @@
+//! Limits on an evaluation: a number of reduction steps, a wall-clock deadline, a
+//! [CancelHandle] another thread can trigger, Ctrl-C once [interrupt_on_sigint] is called, and
+//! the memory and collection sizes. Every engine calls [tick] before a function call and
+//! [check_len] on the values returned by the builtins, the run stops with an [Interrupt] error
+//! once a limit is reached. Calls also hold a [Depth] from [enter], so that deep recursion stops
+//! with [Interrupt::StackOverflow] before the native stack overflows.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{types::MalValue, Result};
+use lazy_static::lazy_static;
+use signal_hook::consts::SIGINT;
+
+/// Calls between two checks of the clock and of the cancellation, which are slower than the fuel
+const CHECK_EVERY: u32 = 1024;
+
+/// Nested calls allowed in any run, the tree walker and `--closures` use the native stack for
+/// each of them
+pub const MAX_DEPTH: usize = 10_000;
+
+/// Longest collection or string a builtin checking its size with [check_size] builds, even
+/// without [Budget::max_len]
+pub const MAX_SIZE: usize = 1 << 24;
+
+/// Native stack the thread evaluating the forms needs for [MAX_DEPTH] nested calls, in a debug
+/// build too
+pub const STACK_SIZE: usize = 256 << 20;
+
+/// Native stack left under which calls stop with [Interrupt::StackOverflow], on threads smaller
+/// than [STACK_SIZE]. Enough for a call through a builtin like `map` in a debug build.
+const STACK_RED_ZONE: usize = 1 << 20;
+
+/// Error stopping a run when a limit of its [Budget] is reached, find it with
+/// [anyhow::Error::downcast_ref]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+    OutOfFuel,
+    Timeout,
+    Cancelled,
+    /// Ctrl-C, see [interrupt_on_sigint]
+    Interrupted,
+    OutOfMemory,
+    /// A builtin returned a collection or a string longer than [Budget::max_len], or would build
+    /// one longer than [MAX_SIZE]
+    TooLarge(usize),
+    /// More than [MAX_DEPTH] nested calls, or less than [STACK_RED_ZONE] of native stack left
+    StackOverflow,
+}
+
+impl fmt::Display for Interrupt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Interrupt::OutOfFuel => write!(f, "Out of fuel"),
+            Interrupt::Timeout => write!(f, "Timed out"),
+            Interrupt::Cancelled => write!(f, "Cancelled"),
+            Interrupt::Interrupted => write!(f, "Interrupted"),
+            Interrupt::OutOfMemory => write!(f, "Out of memory"),
+            Interrupt::TooLarge(len) => write!(f, "Too large, {} items", len),
+            Interrupt::StackOverflow => write!(f, "Stack overflow"),
+        }
+    }
+}
+
+impl std::error::Error for Interrupt {}
+
+/// Limits of a [run], none by default
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Budget {
+    /// Maximum number of function calls
+    pub fuel: Option<u64>,
+    /// Maximum duration of the run
+    pub timeout: Option<Duration>,
+    /// Maximum length of the collections and strings returned by the builtins
+    pub max_len: Option<usize>,
+    /// Maximum number of bytes allocated by the run and not freed yet, see [CountingAlloc]
+    pub memory: Option<usize>,
+}
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+/// Global allocator counting the allocated bytes, needed by [Budget::memory]:
+/// `#[global_allocator] static ALLOC: CountingAlloc = CountingAlloc;`
+pub struct CountingAlloc;
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATED.fetch_add(new_size, Ordering::Relaxed);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+/// Bytes allocated by the process and not freed yet, [None] without [CountingAlloc]
+pub fn allocated() -> Option<usize> {
+    match ALLOCATED.load(Ordering::Relaxed) {
+        0 => None,
+        n => Some(n),
+    }
+}
+
+/// Cancels the runs of the thread it was taken from, see [cancel_handle]
+#[derive(Debug, Default, Clone)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    /// Stops the current run of the thread with [Interrupt::Cancelled]
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+struct State {
+    fuel: Cell<u64>,
+    deadline: Cell<Option<Instant>>,
+    countdown: Cell<u32>,
+    max_len: Cell<usize>,
+    /// Allocated bytes stopping the run
+    memory: Cell<usize>,
+}
+
+thread_local! {
+    // Constant, [tick] runs on every call
+    static STATE: State = const {
+        State {
+            fuel: Cell::new(u64::MAX),
+            deadline: Cell::new(None),
+            countdown: Cell::new(CHECK_EVERY),
+            max_len: Cell::new(usize::MAX),
+            memory: Cell::new(usize::MAX),
+        }
+    };
+    static CANCEL: CancelHandle = CancelHandle::default();
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+lazy_static! {
+    /// Set by SIGINT, the signal is for the whole process
+    static ref INTERRUPTED: Arc<AtomicBool> = Arc::default();
+}
+
+/// Makes Ctrl-C stop the current run with [Interrupt::Interrupted], instead of killing the process
+pub fn interrupt_on_sigint() -> Result<()> {
+    signal_hook::flag::register(SIGINT, INTERRUPTED.clone())?;
+    Ok(())
+}
+
+/// Handle cancelling the runs of this thread, it can be sent to another one
+pub fn cancel_handle() -> CancelHandle {
+    CANCEL.with(CancelHandle::clone)
+}
+
+/// Runs `f` within the budget, a cancellation or Ctrl-C before the start is forgotten.
+/// Nested runs only tighten the limits, and the fuel they use is taken from the outer run.
+pub fn run<T>(budget: Budget, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    let (fuel, deadline, max_len, memory) = STATE.with(|s| {
+        let outer = (
+            s.fuel.get(),
+            s.deadline.get(),
+            s.max_len.get(),
+            s.memory.get(),
+        );
+        let (fuel, deadline, max_len, memory) = outer;
+        s.fuel.set(budget.fuel.map_or(fuel, |f| f.min(fuel)));
+        let timeout = budget.timeout.map(|t| Instant::now() + t);
+        s.deadline.set(match (deadline, timeout) {
+            (Some(d), Some(t)) => Some(d.min(t)),
+            (d, t) => d.or(t),
+        });
+        s.max_len
+            .set(budget.max_len.map_or(max_len, |l| l.min(max_len)));
+        let limit = budget
+            .memory
+            .map(|m| ALLOCATED.load(Ordering::Relaxed).saturating_add(m));
+        s.memory.set(limit.map_or(memory, |l| l.min(memory)));
+        outer
+    });
+    CANCEL.with(|c| c.0.store(false, Ordering::Relaxed));
+    INTERRUPTED.store(false, Ordering::Relaxed);
+    let start = STATE.with(|s| s.fuel.get());
+    let res = f();
+    STATE.with(|s| {
+        s.fuel.set(fuel - (start - s.fuel.get()));
+        s.deadline.set(deadline);
+        s.max_len.set(max_len);
+        s.memory.set(memory);
+    });
+    res
+}
+
+/// Counts a reduction step of the current run, errors once one of its limits is reached
+pub fn tick() -> Result<()> {
+    STATE.with(|s| {
+        let fuel = s.fuel.get();
+        if fuel == 0 {
+            return Err(Interrupt::OutOfFuel.into());
+        }
+        s.fuel.set(fuel - 1);
+        if ALLOCATED.load(Ordering::Relaxed) > s.memory.get() {
+            return Err(Interrupt::OutOfMemory.into());
+        }
+
+        let countdown = s.countdown.get();
+        if countdown > 0 {
+            s.countdown.set(countdown - 1);
+            return Ok(());
+        }
+        s.countdown.set(CHECK_EVERY);
+        if CANCEL.with(CancelHandle::is_cancelled) {
+            return Err(Interrupt::Cancelled.into());
+        }
+        if INTERRUPTED.swap(false, Ordering::Relaxed) {
+            return Err(Interrupt::Interrupted.into());
+        }
+        match s.deadline.get() {
+            Some(deadline) if Instant::now() >= deadline => Err(Interrupt::Timeout.into()),
+            _ => Ok(()),
+        }
+    })
+}
+
+/// A nested call, counted until it is dropped, see [enter]
+#[derive(Debug)]
+pub struct Depth(());
+
+impl Drop for Depth {
+    fn drop(&mut self) {
+        DEPTH.with(|d| d.set(d.get() - 1));
+    }
+}
+
+/// Counts a nested call until the returned [Depth] is dropped, errors with
+/// [Interrupt::StackOverflow] past [MAX_DEPTH] nested calls or near the end of the native stack
+pub fn enter() -> Result<Depth> {
+    if stacker::remaining_stack().is_some_and(|left| left < STACK_RED_ZONE) {
+        return Err(Interrupt::StackOverflow.into());
+    }
+    DEPTH.with(|d| match d.get() {
+        depth if depth >= MAX_DEPTH => Err(Interrupt::StackOverflow.into()),
+        depth => {
+            d.set(depth + 1);
+            Ok(Depth(()))
+        }
+    })
+}
+
+/// Errors when the value returned by a builtin is longer than the current run allows
+pub fn check_len(val: &MalValue) -> Result<()> {
+    match val {
+        MalValue::List(l) | MalValue::Vec(l) => check_size(l.len()),
+        MalValue::Map(m) => check_size(m.len()),
+        MalValue::SortedMap(m) => check_size(m.entries.len()),
+        MalValue::String(s) => check_size(s.len()),
+        _ => Ok(()),
+    }
+}
+
+/// Errors when a builtin would build a collection or a string of this length, to check it
+/// before allocating it
+pub fn check_size(len: usize) -> Result<()> {
+    match STATE.with(|s| s.max_len.get()).min(MAX_SIZE) {
+        max if len > max => Err(Interrupt::TooLarge(len).into()),
+        _ => Ok(()),
+    }
+}
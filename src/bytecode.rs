@@ -0,0 +1,89 @@
+use crate::{env::GlobalCache, symbol::Symbol, types::MalValue};
+use std::rc::Rc;
+
+/// Instruction of the stack VM, operands index the constants, protos or slots of the current
+/// [Chunk]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Const(u32),
+    Nil,
+    True,
+    False,
+    /// Looks the symbol up from the frame environment, through the cache of the chunk
+    GetGlobal {
+        sym: Symbol,
+        cache: u32,
+    },
+    DefGlobal(Symbol),
+    DefPrivate(Symbol),
+    GetLocal(u32),
+    SetLocal(u32),
+    /// Resets the slot before a `let*` reuses it, so closures of a previous scope keep their value
+    ClearLocal(u32),
+    GetUpvalue(u32),
+    /// Pops a value and destructures it with the pattern constant into the slots starting at `slot`
+    Bind {
+        pattern: u32,
+        slot: u32,
+    },
+    Pop,
+    Jump(u32),
+    JumpIfFalse(u32),
+    /// Calls the function below the `argc` arguments on the stack
+    Call(u32),
+    Return,
+    Closure(u32),
+    Vec(u32),
+    /// Builds a map from the values on the stack, the constant holding the list of keys
+    Map(u32),
+    InNs(Symbol),
+    /// Evaluates a `ns` form, the constant holding its arguments
+    Ns(u32),
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub code: Vec<Op>,
+    pub constants: Vec<MalValue>,
+    pub protos: Vec<Rc<Proto>>,
+    /// One per [Op::GetGlobal] of the chunk
+    pub caches: Vec<GlobalCache>,
+}
+
+/// Where a closure takes an upvalue from when it is created
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capture {
+    /// Slot of the enclosing function
+    Local(u32),
+    /// Upvalue of the enclosing function
+    Upvalue(u32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Arity {
+    pub params: MalValue,
+    pub fixed: usize,
+    pub variadic: bool,
+    pub slots: u32,
+    pub chunk: Chunk,
+}
+
+/// Compiled `fn*`, or top level form when it has a single arity without parameters
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Proto {
+    pub name: Option<Symbol>,
+    pub captures: Vec<Capture>,
+    pub arities: Vec<Arity>,
+    /// Source of each arity, as `(params, body)`, for printing
+    pub source: Vec<(MalValue, MalValue)>,
+}
+
+impl Proto {
+    /// Selects the arity with exactly `argc` parameters, or else the variadic one accepting them
+    pub fn select_arity(&self, argc: usize) -> Option<&Arity> {
+        self.arities
+            .iter()
+            .find(|a| !a.variadic && a.fixed == argc)
+            .or_else(|| self.arities.iter().find(|a| a.variadic && a.fixed <= argc))
+    }
+}
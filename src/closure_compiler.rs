@@ -0,0 +1,281 @@
+use std::{
+    cell::{Ref, RefCell},
+    fmt,
+    rc::Rc,
+};
+
+use crate::{
+    budget,
+    destructure::destructure,
+    env::{env_bind, env_capture, env_get_local, env_set, env_set_local, Env, GlobalCache},
+    namespace,
+    printer::pr_str,
+    resolver,
+    sandbox::{self, Capability},
+    symbol::{self, Symbol},
+    types::{arity_error, select_arity, MalMap, MalValue},
+    Result,
+};
+use anyhow::{anyhow, Context};
+
+/// Form compiled to a Rust closure, the special forms being dispatched once when it is compiled
+pub type Code = Box<dyn Fn(&Frame) -> Result<MalValue>>;
+
+/// Environments compiled code runs in: the frames of its locals, and the namespace
+/// environment of its globals that `ns` and `in-ns` replace for the following forms
+pub struct Frame {
+    locals: Rc<Env>,
+    globals: RefCell<Rc<Env>>,
+}
+
+impl Frame {
+    pub fn new(env: Rc<Env>) -> Self {
+        Frame {
+            locals: env.clone(),
+            globals: RefCell::new(env),
+        }
+    }
+
+    pub fn locals(&self) -> &Rc<Env> {
+        &self.locals
+    }
+
+    pub fn globals(&self) -> Ref<'_, Rc<Env>> {
+        self.globals.borrow()
+    }
+
+    fn child(&self, locals: Rc<Env>) -> Self {
+        Frame {
+            locals,
+            globals: RefCell::new(self.globals().clone()),
+        }
+    }
+}
+
+/// Function whose bodies are compiled once, when the enclosing form is compiled,
+/// and shared by every closure created from it
+pub struct Closure {
+    pub name: Option<Symbol>,
+    pub env: Rc<Env>,
+    pub globals: Rc<Env>,
+    pub arities: Rc<Vec<(MalValue, MalValue)>>,
+    /// The arities as written, for printing, the VM printing the source of its protos too
+    pub source: Rc<Vec<(MalValue, MalValue)>>,
+    bodies: Rc<Vec<Code>>,
+}
+
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Closure")
+            .field("name", &self.name)
+            .field("arities", &self.arities)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Compiles the form and runs it in `env`
+pub fn eval(env: &Rc<Env>, ast: &MalValue) -> Result<MalValue> {
+    compile(ast)?(&Frame::new(env.clone()))
+}
+
+/// Calls a compiled closure with the arguments
+pub fn call(closure: &Rc<Closure>, args: Rc<Vec<MalValue>>) -> Result<MalValue> {
+    let arities = &closure.arities;
+    let arity = select_arity(arities, args.len())
+        .ok_or_else(|| arity_error(args.len(), closure.name, arities.iter().map(|(p, _)| p)))?;
+    let body = &closure.bodies[arities
+        .iter()
+        .position(|a| std::ptr::eq(a, arity))
+        .unwrap_or(0)];
+    let this = closure.name.map(|_| MalValue::Code(closure.clone()));
+    let env = env_bind(&closure.env, &arity.0, args, this)?;
+    body(&Frame {
+        locals: env,
+        globals: RefCell::new(closure.globals.clone()),
+    })
+}
+
+/// Compiles a form, `fn*` and `let*` being resolved to slots first
+pub fn compile(ast: &MalValue) -> Result<Code> {
+    Ok(match ast {
+        MalValue::Sym(sym) => {
+            let (sym, cache) = (*sym, GlobalCache::default());
+            Box::new(move |f| {
+                cache
+                    .get(&f.globals(), sym)
+                    .with_context(|| anyhow!("Symbol: '{}' not found", sym))
+            })
+        }
+        MalValue::Local { sym, depth, slot } => {
+            let (sym, depth, slot) = (*sym, *depth, *slot);
+            Box::new(move |f| {
+                env_get_local(f.locals(), depth, slot)
+                    .with_context(|| anyhow!("Local: '{}' not found", sym))
+            })
+        }
+        MalValue::List(list) if !list.is_empty() => compile_list(list)?,
+        MalValue::Vec(vec) => {
+            let items = compile_all(vec)?;
+            Box::new(move |f| {
+                let vals = items.iter().map(|c| c(f)).collect::<Result<_>>()?;
+                Ok(MalValue::Vec(Rc::new(vals)))
+            })
+        }
+        MalValue::Map(map) => {
+            let entries = map
+                .iter()
+                .map(|(k, v)| Ok((k.clone(), compile(v)?)))
+                .collect::<Result<Vec<_>>>()?;
+            Box::new(move |f| {
+                let map = entries
+                    .iter()
+                    .map(|(k, c)| Ok((k.clone(), c(f)?)))
+                    .collect::<Result<MalMap>>()?;
+                Ok(MalValue::Map(Rc::new(map)))
+            })
+        }
+        MalValue::Lambda(lambda) => {
+            let bodies = lambda
+                .arities
+                .iter()
+                .map(|(_, body)| compile(body))
+                .collect::<Result<Vec<_>>>()?;
+            let (lambda, bodies) = (lambda.clone(), Rc::new(bodies));
+            Box::new(move |f| {
+                Ok(MalValue::Code(Rc::new(Closure {
+                    name: lambda.name,
+                    env: env_capture(f.locals(), &lambda)?,
+                    globals: f.globals().clone(),
+                    arities: lambda.arities.clone(),
+                    source: lambda.source.clone(),
+                    bodies: bodies.clone(),
+                })))
+            })
+        }
+        MalValue::Let(l) => {
+            let inits = l
+                .bindings
+                .iter()
+                .map(|(k, v)| Ok((k.clone(), compile(v)?)))
+                .collect::<Result<Vec<_>>>()?;
+            let (slots, body) = (l.slots, compile(&l.body)?);
+            Box::new(move |f| {
+                let frame = f.child(Rc::new(Env::new_frame(
+                    f.locals().clone(),
+                    vec![MalValue::Nil; slots],
+                )));
+                let mut slot = 0;
+                for (k, init) in &inits {
+                    let mut vals = vec![];
+                    destructure(k, init(&frame)?, &mut vals)
+                        .with_context(|| anyhow!("Cannot bind '{}' in let", pr_str(k)))?;
+                    for val in vals {
+                        env_set_local(frame.locals(), slot, val)?;
+                        slot += 1;
+                    }
+                }
+                body(&frame)
+            })
+        }
+        v => {
+            let v = v.clone();
+            Box::new(move |_| Ok(v.clone()))
+        }
+    })
+}
+
+fn compile_all(forms: &[MalValue]) -> Result<Vec<Code>> {
+    forms.iter().map(compile).collect()
+}
+
+fn compile_list(list: &[MalValue]) -> Result<Code> {
+    let tail = &list[1..];
+    let sym = match list[0] {
+        MalValue::Sym(sym) => Some(sym),
+        _ => None,
+    };
+    Ok(match sym {
+        Some(symbol::DEF | symbol::DEF_PRIVATE) => {
+            let (name, val) = match tail {
+                [name, val] => (name.clone(), compile(val)?),
+                _ if tail.len() > 2 => return Err(anyhow!("Too many arguemnts to def")),
+                _ => return Err(anyhow!("Invalid number of arguments to def")),
+            };
+            let private = sym == Some(symbol::DEF_PRIVATE);
+            Box::new(move |f| {
+                let val = val(f)?;
+                let env = f.globals();
+                env_set(&env, &name, val.clone())?;
+                if private {
+                    namespace::set_private(&env, &name);
+                }
+                Ok(val)
+            })
+        }
+        Some(symbol::NS) => {
+            let form = tail.to_vec();
+            Box::new(move |f| {
+                let env = namespace::ns_form(&form)?.env.clone();
+                *f.globals.borrow_mut() = env;
+                Ok(MalValue::Nil)
+            })
+        }
+        Some(symbol::IN_NS) => match tail {
+            [MalValue::Sym(name)] => {
+                let name = *name;
+                Box::new(move |f| {
+                    sandbox::check(Capability::Namespaces)?;
+                    *f.globals.borrow_mut() = namespace::in_ns(name).env.clone();
+                    Ok(MalValue::Nil)
+                })
+            }
+            _ => return Err(anyhow!("in-ns needs a namespace name")),
+        },
+        Some(symbol::LET) => match tail {
+            [bindings, body] => compile(&resolver::resolve_let(bindings, body)?)?,
+            _ => return Err(anyhow!("Invalid number of arguments to let")),
+        },
+        Some(symbol::DO) => {
+            let forms = compile_all(tail)?;
+            Box::new(move |f| {
+                let mut last = MalValue::Nil;
+                for form in &forms {
+                    last = form(f)?;
+                }
+                Ok(last)
+            })
+        }
+        Some(symbol::IF) => {
+            if tail.len() > 3 || tail.len() < 2 {
+                return Err(anyhow!("Invalid number of arguemnts to if"));
+            }
+            let cond = compile(&tail[0])?;
+            let then = compile(&tail[1])?;
+            let otherwise = compile(tail.get(2).unwrap_or(&MalValue::Nil))?;
+            Box::new(move |f| match cond(f)? {
+                MalValue::False | MalValue::Nil => otherwise(f),
+                _ => then(f),
+            })
+        }
+        Some(symbol::FN) => compile(&resolver::resolve_fn(tail)?)?,
+        Some(symbol::DEFN | symbol::DEFN_PRIVATE) => compile(&resolver::expand_defn(list)?)?,
+        _ => {
+            let func = compile(&list[0])?;
+            let args = compile_all(tail)?;
+            Box::new(move |f| {
+                budget::tick()?;
+                let _depth = budget::enter()?;
+                let func = func(f)?;
+                let args = args.iter().map(|c| c(f)).collect::<Result<Vec<_>>>()?;
+                match func {
+                    MalValue::Function(builtin) => {
+                        let val = builtin(&args)?;
+                        budget::check_len(&val)?;
+                        Ok(val)
+                    }
+                    func => func.apply(Rc::new(args)),
+                }
+            })
+        }
+    })
+}
@@ -0,0 +1,363 @@
+use std::rc::Rc;
+
+use crate::{
+    bytecode::{Arity, Capture, Chunk, Op, Proto},
+    destructure::{body_with_defaults, pattern_syms, split_rest, with_defaults},
+    env::GlobalCache,
+    printer::pr_str,
+    resolver::{expand_defn, fn_arities},
+    symbol::{self, Symbol},
+    types::MalValue,
+    Result,
+};
+use anyhow::anyhow;
+use itertools::Itertools;
+
+struct LocalVar {
+    name: Symbol,
+    slot: u32,
+    /// Set once the value is bound, `let*` bindings are only visible to the following ones.
+    /// Closures see all of them, since they run once the whole `let*` is bound.
+    bound: bool,
+}
+
+/// Function being compiled, the arity specific parts are reset for each arity
+#[derive(Default)]
+struct FnState {
+    captures: Vec<Capture>,
+    locals: Vec<LocalVar>,
+    next_slot: u32,
+    max_slots: u32,
+    chunk: Chunk,
+}
+
+enum Var {
+    Local(u32),
+    Upvalue(u32),
+    Global,
+}
+
+/// Compiles forms to bytecode for the [crate::vm]
+#[derive(Default)]
+pub struct Compiler {
+    fns: Vec<FnState>,
+}
+
+/// Compiles a top level form into a [Proto] with a single arity without parameters
+pub fn compile(ast: &MalValue) -> Result<Rc<Proto>> {
+    let mut compiler = Compiler::default();
+    compiler.fns.push(FnState::default());
+    compiler.compile(ast)?;
+    compiler.emit(Op::Return);
+    let state = compiler.fns.pop().unwrap_or_default();
+    Ok(Rc::new(Proto {
+        name: None,
+        captures: vec![],
+        arities: vec![Arity {
+            params: MalValue::Vec(Rc::new(vec![])),
+            fixed: 0,
+            variadic: false,
+            slots: state.max_slots,
+            chunk: state.chunk,
+        }],
+        source: vec![(MalValue::Vec(Rc::new(vec![])), ast.clone())],
+    }))
+}
+
+impl Compiler {
+    fn state(&mut self) -> &mut FnState {
+        self.fns.last_mut().expect("No function being compiled")
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        let code = &mut self.state().chunk.code;
+        code.push(op);
+        code.len() - 1
+    }
+
+    fn constant(&mut self, val: MalValue) -> u32 {
+        let constants = &mut self.state().chunk.constants;
+        constants.push(val);
+        (constants.len() - 1) as u32
+    }
+
+    fn patch_jump(&mut self, at: usize) {
+        let target = self.state().chunk.code.len() as u32;
+        match &mut self.state().chunk.code[at] {
+            Op::Jump(t) | Op::JumpIfFalse(t) => *t = target,
+            op => unreachable!("Cannot patch {:?}", op),
+        }
+    }
+
+    fn declare(&mut self, name: Symbol, bound: bool) -> u32 {
+        let state = self.state();
+        let slot = state.next_slot;
+        state.next_slot += 1;
+        state.max_slots = state.max_slots.max(state.next_slot);
+        state.locals.push(LocalVar { name, slot, bound });
+        slot
+    }
+
+    fn resolve(&mut self, level: usize, sym: Symbol, deferred: bool) -> Var {
+        let local = self.fns[level]
+            .locals
+            .iter()
+            .rev()
+            .find(|l| l.name == sym && (deferred || l.bound));
+        if let Some(local) = local {
+            return Var::Local(local.slot);
+        }
+        if level == 0 {
+            return Var::Global;
+        }
+        let capture = match self.resolve(level - 1, sym, true) {
+            Var::Local(slot) => Capture::Local(slot),
+            Var::Upvalue(i) => Capture::Upvalue(i),
+            Var::Global => return Var::Global,
+        };
+        let captures = &mut self.fns[level].captures;
+        let i = match captures.iter().position(|c| *c == capture) {
+            Some(i) => i,
+            None => {
+                captures.push(capture);
+                captures.len() - 1
+            }
+        };
+        Var::Upvalue(i as u32)
+    }
+
+    pub fn compile(&mut self, ast: &MalValue) -> Result<()> {
+        match ast {
+            MalValue::Nil => self.emit(Op::Nil),
+            MalValue::True => self.emit(Op::True),
+            MalValue::False => self.emit(Op::False),
+            MalValue::Sym(sym) => {
+                let level = self.fns.len() - 1;
+                match self.resolve(level, *sym, false) {
+                    Var::Local(slot) => self.emit(Op::GetLocal(slot)),
+                    Var::Upvalue(i) => self.emit(Op::GetUpvalue(i)),
+                    Var::Global => {
+                        let caches = &mut self.state().chunk.caches;
+                        caches.push(GlobalCache::default());
+                        let cache = (caches.len() - 1) as u32;
+                        self.emit(Op::GetGlobal { sym: *sym, cache })
+                    }
+                }
+            }
+            MalValue::List(list) if !list.is_empty() => return self.compile_list(list),
+            MalValue::Vec(vec) => {
+                for v in vec.iter() {
+                    self.compile(v)?;
+                }
+                self.emit(Op::Vec(vec.len() as u32))
+            }
+            MalValue::Map(map) => {
+                let mut keys = vec![];
+                for (k, v) in map.iter() {
+                    self.compile(v)?;
+                    keys.push(MalValue::String(k.as_str().into()));
+                }
+                let keys = self.constant(MalValue::List(Rc::new(keys)));
+                self.emit(Op::Map(keys))
+            }
+            v => {
+                let i = self.constant(v.clone());
+                self.emit(Op::Const(i))
+            }
+        };
+        Ok(())
+    }
+
+    fn compile_list(&mut self, list: &[MalValue]) -> Result<()> {
+        let tail = &list[1..];
+        match &list[0] {
+            MalValue::Sym(symbol::DEF | symbol::DEF_PRIVATE) => match tail {
+                [MalValue::Sym(name), val] => {
+                    self.compile(val)?;
+                    match list[0] {
+                        MalValue::Sym(symbol::DEF) => self.emit(Op::DefGlobal(*name)),
+                        _ => self.emit(Op::DefPrivate(*name)),
+                    };
+                }
+                [name, _] => {
+                    return Err(anyhow!(
+                        "Invalid key to set from environment: {}",
+                        pr_str(name)
+                    ))
+                }
+                _ => return Err(anyhow!("Invalid number of arguments to def")),
+            },
+            MalValue::Sym(symbol::NS) => {
+                let form = self.constant(MalValue::List(Rc::new(tail.to_vec())));
+                self.emit(Op::Ns(form));
+            }
+            MalValue::Sym(symbol::IN_NS) => match tail {
+                [MalValue::Sym(name)] => {
+                    self.emit(Op::InNs(*name));
+                }
+                _ => return Err(anyhow!("in-ns needs a namespace name")),
+            },
+            MalValue::Sym(symbol::LET) => match tail {
+                [bindings, body] => self.compile_let(bindings, body)?,
+                _ => return Err(anyhow!("Invalid number of arguments to let")),
+            },
+            MalValue::Sym(symbol::DO) => {
+                if tail.is_empty() {
+                    self.emit(Op::Nil);
+                }
+                for (i, v) in tail.iter().enumerate() {
+                    if i > 0 {
+                        self.emit(Op::Pop);
+                    }
+                    self.compile(v)?;
+                }
+            }
+            MalValue::Sym(symbol::IF) => {
+                if tail.len() > 3 || tail.len() < 2 {
+                    return Err(anyhow!("Invalid number of arguemnts to if"));
+                }
+                self.compile(&tail[0])?;
+                let to_else = self.emit(Op::JumpIfFalse(0));
+                self.compile(&tail[1])?;
+                let to_end = self.emit(Op::Jump(0));
+                self.patch_jump(to_else);
+                self.compile(tail.get(2).unwrap_or(&MalValue::Nil))?;
+                self.patch_jump(to_end);
+            }
+            MalValue::Sym(symbol::FN) => self.compile_fn(tail)?,
+            MalValue::Sym(symbol::DEFN | symbol::DEFN_PRIVATE) => {
+                self.compile(&expand_defn(list)?)?
+            }
+            _ => {
+                for v in list {
+                    self.compile(v)?;
+                }
+                self.emit(Op::Call(tail.len() as u32));
+            }
+        }
+        Ok(())
+    }
+
+    fn compile_let(&mut self, bindings: &MalValue, body: &MalValue) -> Result<()> {
+        let bindings = match bindings {
+            MalValue::List(b) | MalValue::Vec(b) if b.len().is_multiple_of(2) => b,
+            MalValue::List(_) | MalValue::Vec(_) => {
+                return Err(anyhow!(
+                    "Invalid let  bindings, needs to have a key, value pair"
+                ))
+            }
+            v => {
+                return Err(anyhow!(
+                    "Let bindings needs a list, obtained: {}",
+                    pr_str(v)
+                ))
+            }
+        };
+        let bindings = with_defaults(bindings)?;
+        let (locals, first_slot) = (self.state().locals.len(), self.state().next_slot);
+
+        let mut names = vec![];
+        for k in bindings.iter().step_by(2) {
+            pattern_syms(k, &mut names)?;
+        }
+        for name in names {
+            let slot = self.declare(name, false);
+            self.emit(Op::ClearLocal(slot));
+        }
+
+        let mut next = locals;
+        for (k, v) in bindings.iter().tuples() {
+            self.compile(v)?;
+            let slot = self.state().locals.get(next).map_or(0, |l| l.slot);
+            match k {
+                MalValue::Sym(_) => self.emit(Op::SetLocal(slot)),
+                pattern => {
+                    let pattern = self.constant(pattern.clone());
+                    self.emit(Op::Bind { pattern, slot })
+                }
+            };
+            let mut bound = vec![];
+            pattern_syms(k, &mut bound)?;
+            for l in &mut self.state().locals[next..next + bound.len()] {
+                l.bound = true;
+            }
+            next += bound.len();
+        }
+        self.compile(body)?;
+
+        let state = self.state();
+        state.locals.truncate(locals);
+        state.next_slot = first_slot;
+        Ok(())
+    }
+
+    fn compile_fn(&mut self, form: &[MalValue]) -> Result<()> {
+        let (name, source) = fn_arities(form)?;
+
+        self.fns.push(FnState::default());
+        let arities = self.compile_arities(name, &source);
+        let state = self.fns.pop().unwrap_or_default();
+        let proto = Proto {
+            name,
+            captures: state.captures,
+            arities: arities?,
+            source,
+        };
+
+        let protos = &mut self.state().chunk.protos;
+        protos.push(Rc::new(proto));
+        let i = (protos.len() - 1) as u32;
+        self.emit(Op::Closure(i));
+        Ok(())
+    }
+
+    fn compile_arities(
+        &mut self,
+        name: Option<Symbol>,
+        source: &[(MalValue, MalValue)],
+    ) -> Result<Vec<Arity>> {
+        let mut arities: Vec<Arity> = vec![];
+        for (params, body) in source {
+            let (fixed, variadic) = match params {
+                MalValue::List(p) | MalValue::Vec(p) => {
+                    let (fixed, rest) = split_rest(p)?;
+                    (fixed.len(), rest.is_some())
+                }
+                v => {
+                    return Err(anyhow!(
+                        "Invalid parameters, needs to be a vector: {}",
+                        pr_str(v)
+                    ))
+                }
+            };
+            if variadic && arities.iter().any(|a| a.variadic) {
+                return Err(anyhow!("Cannot have more than one variadic arity"));
+            }
+            if !variadic && arities.iter().any(|a| !a.variadic && a.fixed == fixed) {
+                return Err(anyhow!("Cannot have two arities with {} parameters", fixed));
+            }
+
+            let state = self.state();
+            state.locals.clear();
+            state.next_slot = 0;
+            state.max_slots = 0;
+            let mut names = Vec::from_iter(name);
+            pattern_syms(params, &mut names)?;
+            for name in names {
+                self.declare(name, true);
+            }
+            self.compile(&body_with_defaults(params, body)?)?;
+            self.emit(Op::Return);
+
+            let state = self.state();
+            arities.push(Arity {
+                params: params.clone(),
+                fixed,
+                variadic,
+                slots: state.max_slots,
+                chunk: std::mem::take(&mut state.chunk),
+            });
+        }
+        Ok(arities)
+    }
+}
@@ -0,0 +1,240 @@
+use std::rc::Rc;
+
+use crate::{
+    printer::pr_str,
+    reader::hash_map,
+    symbol::{self, Symbol},
+    types::{key_value, MalMap, MalValue},
+    Result,
+};
+use anyhow::anyhow;
+
+/// Splits a sequential pattern on `&`, returning the fixed patterns and the rest pattern if any
+pub fn split_rest(pattern: &[MalValue]) -> Result<(&[MalValue], Option<&MalValue>)> {
+    match pattern
+        .iter()
+        .position(|p| *p == MalValue::Sym(symbol::AMPERSAND))
+    {
+        None => Ok((pattern, None)),
+        Some(i) if i + 2 == pattern.len() => Ok((&pattern[..i], Some(&pattern[i + 1]))),
+        Some(_) => Err(anyhow!(
+            "Invalid binding, '&' needs to be followed by a single parameter: {}",
+            pr_str(&MalValue::Vec(Rc::new(pattern.to_vec())))
+        )),
+    }
+}
+
+/// Collects the symbols bound by a pattern, in the order [destructure] binds their values
+pub fn pattern_syms(pattern: &MalValue, syms: &mut Vec<Symbol>) -> Result<()> {
+    match pattern {
+        MalValue::Sym(sym) => syms.push(*sym),
+        MalValue::List(p) | MalValue::Vec(p) => {
+            let (fixed, rest) = split_rest(p)?;
+            for p in fixed.iter().chain(rest) {
+                pattern_syms(p, syms)?;
+            }
+        }
+        MalValue::Map(map) => {
+            for key in map.keys() {
+                if !MAP_OPTIONS.contains(&key.as_str()) {
+                    return Err(anyhow!("Unknown map destructuring option: {}", key));
+                }
+            }
+            let defaults = defaults(map)?;
+            if let Some(key) = defaults
+                .keys()
+                .find(|k| !matches!(key_value(k), MalValue::Sym(_)))
+            {
+                return Err(anyhow!(":or defaults are keyed by symbol, got: {}", key));
+            }
+            for key in [":keys", ":strs"] {
+                match map.get(key) {
+                    Some(MalValue::List(names) | MalValue::Vec(names)) => {
+                        for name in names.iter() {
+                            match name {
+                                MalValue::Sym(sym) => {
+                                    syms.push(*sym);
+                                    if defaults.contains_key(sym.name()) {
+                                        syms.push(found(*sym));
+                                    }
+                                }
+                                v => {
+                                    return Err(anyhow!(
+                                        "Invalid {} binding, needs to be a symbol: {}",
+                                        key,
+                                        pr_str(v)
+                                    ))
+                                }
+                            }
+                        }
+                    }
+                    Some(v) => return Err(anyhow!("{} needs a vector, got: {}", key, pr_str(v))),
+                    None => (),
+                }
+            }
+            match map.get(":as") {
+                Some(MalValue::Sym(sym)) => syms.push(*sym),
+                Some(v) => return Err(anyhow!(":as needs a symbol, got: {}", pr_str(v))),
+                None => (),
+            }
+        }
+        v => {
+            return Err(anyhow!(
+                "Invalid binding, needs to be a symbol, a vector or a map: {}",
+                pr_str(v)
+            ))
+        }
+    }
+    Ok(())
+}
+
+const MAP_OPTIONS: [&str; 4] = [":keys", ":strs", ":or", ":as"];
+
+/// `:or` defaults of a map pattern, keyed by the symbol they are the default of
+fn defaults(opts: &MalMap) -> Result<Rc<MalMap>> {
+    match opts.get(":or") {
+        Some(MalValue::Map(d)) => Ok(d.clone()),
+        Some(v) => Err(anyhow!(":or needs a map, got: {}", pr_str(v))),
+        None => Ok(Rc::default()),
+    }
+}
+
+/// Local telling whether the key of a symbol with a `:or` default was found, its name can't be
+/// read so it never clashes with the symbols of the source
+fn found(sym: Symbol) -> Symbol {
+    Symbol::new(&format!("{} found", sym))
+}
+
+/// Pushes the bindings giving their `:or` default to the symbols of the pattern whose key is
+/// missing, `name (if found name default)`, to follow the binding of the pattern. This way a
+/// default is only evaluated when its key is missing, by any engine.
+pub fn default_bindings(pattern: &MalValue, bindings: &mut Vec<MalValue>) -> Result<()> {
+    match pattern {
+        MalValue::List(p) | MalValue::Vec(p) => {
+            let (fixed, rest) = split_rest(p)?;
+            for p in fixed.iter().chain(rest) {
+                default_bindings(p, bindings)?;
+            }
+        }
+        MalValue::Map(opts) => {
+            let defaults = defaults(opts)?;
+            for key in [":keys", ":strs"] {
+                if let Some(MalValue::List(names) | MalValue::Vec(names)) = opts.get(key) {
+                    for name in names.iter() {
+                        if let MalValue::Sym(sym) = name {
+                            if let Some(default) = defaults.get(sym.name()) {
+                                bindings.push(name.clone());
+                                bindings.push(MalValue::List(Rc::new(vec![
+                                    MalValue::Sym(symbol::IF),
+                                    MalValue::Sym(found(*sym)),
+                                    name.clone(),
+                                    default.clone(),
+                                ])));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+/// `let*` bindings with the [default_bindings] of each pattern after it
+pub fn with_defaults(bindings: &[MalValue]) -> Result<Vec<MalValue>> {
+    let mut expanded = vec![];
+    for pair in bindings.chunks(2) {
+        expanded.extend_from_slice(pair);
+        default_bindings(&pair[0], &mut expanded)?;
+    }
+    Ok(expanded)
+}
+
+/// Body of a function binding the [default_bindings] of its parameters first
+pub fn body_with_defaults(params: &MalValue, body: &MalValue) -> Result<MalValue> {
+    let mut bindings = vec![];
+    default_bindings(params, &mut bindings)?;
+    if bindings.is_empty() {
+        return Ok(body.clone());
+    }
+    Ok(MalValue::List(Rc::new(vec![
+        MalValue::Sym(symbol::LET),
+        MalValue::Vec(Rc::new(bindings)),
+        body.clone(),
+    ])))
+}
+
+/// Binds `val` to the pattern, pushing the values of the bound symbols in the order of
+/// [pattern_syms]. Missing values of nested patterns are bound to nil, like the map keys. A symbol
+/// with a `:or` default is followed by whether its key was found, for its [default_bindings].
+pub fn destructure(pattern: &MalValue, val: MalValue, vals: &mut Vec<MalValue>) -> Result<()> {
+    match pattern {
+        MalValue::Sym(_) => vals.push(val),
+        MalValue::List(p) | MalValue::Vec(p) => {
+            let seq = match &val {
+                MalValue::List(s) | MalValue::Vec(s) => s.clone(),
+                MalValue::Nil => Rc::new(vec![]),
+                v => {
+                    return Err(anyhow!(
+                        "Cannot destructure {} with the pattern {}",
+                        pr_str(v),
+                        pr_str(pattern)
+                    ))
+                }
+            };
+            let (fixed, rest) = split_rest(p)?;
+            for (i, p) in fixed.iter().enumerate() {
+                destructure(p, seq.get(i).cloned().unwrap_or(MalValue::Nil), vals)?;
+            }
+            if let Some(rest) = rest {
+                let tail = seq.get(fixed.len()..).unwrap_or_default().to_vec();
+                destructure(rest, MalValue::List(Rc::new(tail)), vals)?;
+            }
+        }
+        MalValue::Map(opts) => {
+            let map = match &val {
+                MalValue::Map(m) => m.clone(),
+                MalValue::SortedMap(m) => Rc::new(m.entries.clone()),
+                MalValue::Nil => Rc::new(MalMap::default()),
+                // Rest arguments given as key value pairs
+                MalValue::List(s) | MalValue::Vec(s) if s.len().is_multiple_of(2) => {
+                    match hash_map(s.to_vec())? {
+                        MalValue::Map(m) => m,
+                        _ => Rc::new(MalMap::default()),
+                    }
+                }
+                v => {
+                    return Err(anyhow!(
+                        "Cannot destructure {} with the pattern {}",
+                        pr_str(v),
+                        pr_str(pattern)
+                    ))
+                }
+            };
+            let defaults = defaults(opts)?;
+            for (key, open, close) in [(":keys", ":", ""), (":strs", "\"", "\"")] {
+                if let Some(MalValue::List(names) | MalValue::Vec(names)) = opts.get(key) {
+                    for name in names.iter() {
+                        let name = pr_str(name);
+                        let val = map.get(&format!("{}{}{}", open, name, close));
+                        vals.push(val.cloned().unwrap_or(MalValue::Nil));
+                        if defaults.contains_key(&name) {
+                            vals.push(val.is_some().into());
+                        }
+                    }
+                }
+            }
+            if opts.contains_key(":as") {
+                vals.push(val);
+            }
+        }
+        v => {
+            return Err(anyhow!(
+                "Invalid binding, needs to be a symbol, a vector or a map: {}",
+                pr_str(v)
+            ))
+        }
+    }
+    Ok(())
+}
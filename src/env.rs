@@ -1,13 +1,28 @@
 use crate::Result;
-use crate::{printer::pr_str, types::MalValue};
-use anyhow::anyhow;
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use crate::{
+    destructure::{destructure, split_rest},
+    gc::{self, LiveEnv},
+    namespace,
+    printer::pr_str,
+    symbol::{Symbol, SymbolMap},
+    types::{Lambda, MalValue},
+};
+use anyhow::{anyhow, Context};
+use std::{
+    cell::{Cell, RefCell},
+    rc::{Rc, Weak},
+};
 
 // pub type MalFn = Box<dyn FnOnce(&Vec<MalValue>) -> Result<MalValue>>;
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
 pub struct Env {
-    parent: Option<Rc<Env>>,
-    data: RefCell<HashMap<String, MalValue>>,
+    pub(crate) parent: Option<Rc<Env>>,
+    pub(crate) data: RefCell<SymbolMap<MalValue>>,
+    pub(crate) slots: RefCell<Vec<MalValue>>,
+    ns: Option<Symbol>,
+    /// Bumped on the root environment by every definition, see [env_version]
+    version: Cell<u64>,
+    live: LiveEnv,
 }
 
 impl Env {
@@ -16,6 +31,10 @@ impl Env {
         Self {
             parent: None,
             data: RefCell::default(),
+            slots: RefCell::default(),
+            ns: None,
+            version: Cell::default(),
+            live: LiveEnv::default(),
         }
     }
 
@@ -23,18 +42,70 @@ impl Env {
         Self {
             parent: Some(parent),
             data: RefCell::default(),
+            slots: RefCell::default(),
+            ns: None,
+            version: Cell::default(),
+            live: LiveEnv::default(),
         }
     }
+
+    /// Creates a child [Self] for resolved locals, accessed by slot instead of by symbol
+    pub fn new_frame(parent: Rc<Self>, slots: Vec<MalValue>) -> Self {
+        Self {
+            parent: Some(parent),
+            data: RefCell::default(),
+            slots: RefCell::new(slots),
+            ns: None,
+            version: Cell::default(),
+            live: LiveEnv::default(),
+        }
+    }
+
+    /// Creates the root [Self] of the namespace `name`, on top of `parent`
+    pub fn new_ns(parent: Rc<Self>, name: Symbol) -> Self {
+        Self {
+            parent: Some(parent),
+            data: RefCell::default(),
+            slots: RefCell::default(),
+            ns: Some(name),
+            version: Cell::default(),
+            live: LiveEnv::default(),
+        }
+    }
+
+    /// Name of the namespace owning this environment, [None] for the core environment
+    pub fn ns_name(&self) -> Option<Symbol> {
+        match self.ns {
+            Some(ns) => Some(ns),
+            None => self.parent.as_ref().and_then(|p| p.ns_name()),
+        }
+    }
+
+    /// Checks if the symbol is defined directly in this environment, without looking at the parents
+    pub fn contains_sym(&self, sym: Symbol) -> bool {
+        self.data.borrow().contains_key(&sym)
+    }
+
+    /// Symbols defined directly in this environment
+    pub fn syms(&self) -> Vec<Symbol> {
+        self.data.borrow().keys().cloned().collect()
+    }
+
+    /// Gets the symbol defined directly in this environment, without looking at the parents
+    pub fn get_own_sym(&self, sym: Symbol) -> Option<MalValue> {
+        self.data.borrow().get(&sym).cloned()
+    }
 }
 
-pub fn env_set_sym(env: &Rc<Env>, sym: String, val: MalValue) {
+pub fn env_set_sym(env: &Rc<Env>, sym: Symbol, val: MalValue) {
     env.data.borrow_mut().insert(sym, val);
+    bump_version(env);
 }
 
 pub fn env_set(env: &Rc<Env>, key: &MalValue, val: MalValue) -> Result<()> {
     match key {
         MalValue::Sym(sym) => {
-            env_set_sym(env, sym.to_string(), val);
+            env_set_sym(env, *sym, val);
             Ok(())
         }
         v => Err(anyhow!(
@@ -44,47 +115,209 @@ pub fn env_set(env: &Rc<Env>, key: &MalValue, val: MalValue) -> Result<()> {
     }
 }
 
-pub fn env_find(env: &Rc<Env>, sym: &String) -> Option<Rc<Env>> {
-    if env.data.borrow().contains_key(sym) {
+fn root(env: &Env) -> &Env {
+    let mut current = env;
+    while let Some(parent) = &current.parent {
+        current = parent;
+    }
+    current
+}
+
+/// Version of the definitions visible from `env`, changed whenever a symbol is defined or referred
+pub fn env_version(env: &Env) -> u64 {
+    root(env).version.get()
+}
+
+/// Invalidates the [GlobalCache]s of every environment sharing the root of `env`
+pub fn bump_version(env: &Env) {
+    let root = root(env);
+    root.version.set(root.version.get() + 1);
+}
+
+/// Global symbol resolved at a call site, reused while looked up from the same environment
+/// and nothing was defined since. Only the VM and the closure backend have call sites to keep
+/// them in, the tree walker looks every symbol up with [env_get_sym].
+#[derive(Debug, Default)]
+pub struct GlobalCache(RefCell<Option<(u64, Weak<Env>, MalValue)>>);
+
+impl GlobalCache {
+    pub fn get(&self, env: &Rc<Env>, sym: Symbol) -> Option<MalValue> {
+        let version = env_version(env);
+        if let Some((v, from, val)) = &*self.0.borrow() {
+            if *v == version && std::ptr::eq(from.as_ptr(), Rc::as_ptr(env)) {
+                return Some(val.clone());
+            }
+        }
+        let val = env_get_sym(env, sym)?;
+        *self.0.borrow_mut() = Some((version, Rc::downgrade(env), val.clone()));
+        Some(val)
+    }
+}
+
+/// Caches are scratch state, a fresh one behaves the same
+impl Clone for GlobalCache {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl PartialEq for GlobalCache {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for GlobalCache {}
+
+pub fn env_find(env: &Rc<Env>, sym: Symbol) -> Option<Rc<Env>> {
+    if env.data.borrow().contains_key(&sym) {
         Some(env.clone())
     } else {
-        env.parent.clone().map(|p| env_find(&p, sym)).flatten()
+        env.parent.as_ref().and_then(|p| env_find(p, sym))
     }
 }
 
-pub fn env_bind(env: &Rc<Env>, bindings: &MalValue, exps: Rc<Vec<MalValue>>) -> Result<Rc<Env>> {
+/// Binds the arguments to the slots of a new frame, in the order of [pattern_syms] on the
+/// parameters. Parameters after `&` get the remaining arguments as a list.
+/// Named functions get themselves as `this` in the first slot, before the parameters.
+pub fn env_bind(
+    env: &Rc<Env>,
+    bindings: &MalValue,
+    exps: Rc<Vec<MalValue>>,
+    this: Option<MalValue>,
+) -> Result<Rc<Env>> {
+    let slots = bind_params(bindings, exps, this)?;
+    Ok(Rc::new(Env::new_frame(env.clone(), slots)))
+}
+
+/// Destructures the arguments into the slot values [env_bind] gives to a new frame
+pub fn bind_params(
+    bindings: &MalValue,
+    exps: Rc<Vec<MalValue>>,
+    this: Option<MalValue>,
+) -> Result<Vec<MalValue>> {
     match bindings {
-        MalValue::List(bindings) | MalValue::Vec(bindings) => {
-            if bindings.len() != exps.len() {
+        MalValue::List(params) | MalValue::Vec(params) => {
+            let (fixed, rest) = split_rest(params)?;
+            if exps.len() < fixed.len() {
                 return Err(anyhow!(
-                    "Bindings doesn't match the number of expr: {:?} vs {:?}",
-                    bindings,
-                    exps
+                    "Missing argument for parameter '{}' of {}, expected {} got {}",
+                    pr_str(&fixed[exps.len()]),
+                    pr_str(bindings),
+                    fixed.len(),
+                    exps.len()
                 ));
             }
+            if rest.is_none() && exps.len() > fixed.len() {
+                return Err(anyhow!(
+                    "Too many arguments for {}, expected {} got {}",
+                    pr_str(bindings),
+                    fixed.len(),
+                    exps.len()
+                ));
+            }
+
+            if this.is_none()
+                && rest.is_none()
+                && fixed.iter().all(|p| matches!(p, MalValue::Sym(_)))
+            {
+                return Ok(Rc::try_unwrap(exps).unwrap_or_else(|exps| exps.to_vec()));
+            }
 
-            let env = Rc::new(Env::new_child(env.clone()));
-            for (i, b) in bindings.iter().enumerate() {
-                env_set(&env, b, exps[i].clone())?;
+            let mut slots = Vec::with_capacity(params.len() + 1);
+            slots.extend(this);
+            for (p, v) in fixed.iter().zip(exps.iter()) {
+                destructure(p, v.clone(), &mut slots)
+                    .with_context(|| anyhow!("Cannot bind parameter '{}'", pr_str(p)))?;
             }
-            Ok(env)
+            if let Some(rest) = rest {
+                let tail = MalValue::List(Rc::new(exps[fixed.len()..].to_vec()));
+                destructure(rest, tail, &mut slots)
+                    .with_context(|| anyhow!("Cannot bind parameter '{}'", pr_str(rest)))?;
+            }
+            Ok(slots)
         }
         v => Err(anyhow!(
             "Invalid binding, needs to be a vector: {}",
-            pr_str(&v)
+            pr_str(v)
         )),
     }
 }
 
-pub fn env_get_sym(env: &Rc<Env>, sym: &String) -> Option<MalValue> {
-    let env = env_find(env, sym)?;
-    let val = env.data.borrow().get(sym).cloned();
+/// Gets the resolved local in `slot` of the environment `depth` levels up
+pub fn env_get_local(env: &Rc<Env>, depth: usize, slot: usize) -> Option<MalValue> {
+    let mut current = env;
+    for _ in 0..depth {
+        current = current.parent.as_ref()?;
+    }
+    let val = current.slots.borrow().get(slot).cloned();
     val
 }
 
+/// Sets the resolved local in `slot` of this environment
+pub fn env_set_local(env: &Rc<Env>, slot: usize, val: MalValue) -> Result<()> {
+    match env.slots.borrow_mut().get_mut(slot) {
+        Some(s) => {
+            *s = val;
+            Ok(())
+        }
+        None => Err(anyhow!("Invalid local slot: {}", slot)),
+    }
+}
+
+/// Global environment of `env`, past the frames of the locals
+pub fn env_globals(env: &Rc<Env>) -> &Rc<Env> {
+    let mut current = env;
+    while let (None, Some(parent)) = (current.ns, &current.parent) {
+        current = parent;
+    }
+    current
+}
+
+/// Environment of a closure created in `env`: the frame of the locals it captured on top of the
+/// global environment, which is used directly when it captures nothing
+pub fn env_capture(env: &Rc<Env>, lambda: &Lambda) -> Result<Rc<Env>> {
+    let parent = if lambda.keeps_env {
+        env
+    } else if lambda.captures.is_empty() {
+        return Ok(env_globals(env).clone());
+    } else {
+        env_globals(env)
+    };
+    let slots = lambda
+        .captures
+        .iter()
+        .map(|&(depth, slot)| {
+            env_get_local(env, depth, slot).ok_or_else(|| anyhow!("Invalid local slot: {}", slot))
+        })
+        .collect::<Result<_>>()?;
+    let frame = Rc::new(Env::new_frame(parent.clone(), slots));
+    if lambda.keeps_env {
+        // The closure can be bound in the frames it keeps
+        gc::track_env(&frame);
+    }
+    Ok(frame)
+}
+
+pub fn env_get_sym(env: &Rc<Env>, sym: Symbol) -> Option<MalValue> {
+    if let Some((ns, name)) = sym.qualified() {
+        return namespace::resolve_qualified(env, ns, name);
+    }
+    let mut current = env;
+    loop {
+        if let Some(val) = current.data.borrow().get(&sym) {
+            return Some(val.clone());
+        }
+        match &current.parent {
+            Some(parent) => current = parent,
+            None => return namespace::resolve_referred(env, sym),
+        }
+    }
+}
+
 pub fn env_get(env: &Rc<Env>, key: &MalValue) -> Result<Option<MalValue>> {
     match key {
-        MalValue::Sym(sym) => Ok(env_get_sym(env, sym)),
+        MalValue::Sym(sym) => Ok(env_get_sym(env, *sym)),
         v => Err(anyhow!(
             "Invalid key to get from environment: {}",
             pr_str(v)
@@ -0,0 +1,326 @@
+//! Cycle collector for the environments and closures.
+//!
+//! Closures keep their environment alive, so a closure stored in the environment it captured,
+//! like a recursive function bound by `let*` or a closure in an upvalue cell of the [crate::vm]
+//! pointing back to itself, is never freed by reference counting alone.
+//!
+//! The environments and cells captured by closures are tracked as candidates. Collecting finds
+//! every environment, cell and closure reachable from them, and subtracts from their reference
+//! counts the references they hold on each other: the ones left with references from elsewhere,
+//! like the namespaces or the Rust stack of a running evaluation, are alive with everything they
+//! reach. The rest are only referenced from garbage cycles, which are broken by clearing their
+//! values.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::{Rc, Weak},
+};
+
+use crate::{env::Env, types::MalValue};
+
+/// Number of candidates triggering a collection, doubled when most of them are still alive
+const THRESHOLD: usize = 10_000;
+
+/// Counts the live environments for [stats], held by each [Env]
+#[derive(Debug, PartialEq, Eq)]
+pub struct LiveEnv(());
+
+impl Default for LiveEnv {
+    fn default() -> Self {
+        LIVE_ENVS.with(|n| n.set(n.get() + 1));
+        LiveEnv(())
+    }
+}
+
+impl Clone for LiveEnv {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl Drop for LiveEnv {
+    fn drop(&mut self) {
+        LIVE_ENVS.with(|n| n.set(n.get() - 1));
+    }
+}
+
+/// Heap statistics, see [stats]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// Environments currently allocated
+    pub envs: usize,
+    /// Environments and cells tracked as possible members of cycles
+    pub tracked: usize,
+    /// Collections run so far
+    pub collections: usize,
+    /// Environments and cells cleared by the collections so far
+    pub collected: usize,
+}
+
+type Upvalue = Rc<RefCell<MalValue>>;
+
+enum Weakref {
+    Env(Weak<Env>),
+    Upvalue(Weak<RefCell<MalValue>>),
+}
+
+/// Environment, upvalue cell or closure, the objects whose references are counted by a collection
+enum Node {
+    Env(Rc<Env>),
+    Upvalue(Upvalue),
+    Closure(MalValue),
+}
+
+/// Borrowed reference to a [Node] found in the values of another
+#[derive(Clone, Copy)]
+enum NodeRef<'a> {
+    Env(&'a Rc<Env>),
+    Upvalue(&'a Upvalue),
+    Closure(&'a MalValue),
+}
+
+struct Candidates {
+    nodes: HashMap<usize, Weakref>,
+    threshold: usize,
+}
+
+thread_local! {
+    static CANDIDATES: RefCell<Candidates> = RefCell::new(Candidates {
+        nodes: HashMap::new(),
+        threshold: THRESHOLD,
+    });
+    static LIVE_ENVS: Cell<usize> = const { Cell::new(0) };
+    static STATS: Cell<Stats> = Cell::new(Stats::default());
+}
+
+/// Tracks an environment captured by a closure, it may now be part of a cycle
+pub fn track_env(env: &Rc<Env>) {
+    track(Rc::as_ptr(env) as usize, || {
+        Weakref::Env(Rc::downgrade(env))
+    });
+}
+
+/// Tracks an upvalue cell captured by a closure, it may now be part of a cycle
+pub fn track_upvalue(cell: &Upvalue) {
+    track(Rc::as_ptr(cell) as usize, || {
+        Weakref::Upvalue(Rc::downgrade(cell))
+    });
+}
+
+fn track(addr: usize, weak: impl FnOnce() -> Weakref) {
+    // The weak reference keeps the allocation, an address is not reused while it is tracked
+    let full = CANDIDATES.with(|c| {
+        let mut c = c.borrow_mut();
+        c.nodes.entry(addr).or_insert_with(weak);
+        c.nodes.len() >= c.threshold
+    });
+    if full {
+        collect();
+        CANDIDATES.with(|c| {
+            let mut c = c.borrow_mut();
+            c.threshold = THRESHOLD.max(2 * c.nodes.len());
+        });
+    }
+}
+
+/// Current heap statistics
+pub fn stats() -> Stats {
+    Stats {
+        envs: LIVE_ENVS.with(Cell::get),
+        tracked: CANDIDATES.with(|c| c.borrow().nodes.len()),
+        ..STATS.with(Cell::get)
+    }
+}
+
+/// Breaks the garbage cycles reachable from the tracked environments and cells, returns the
+/// number of environments and cells cleared
+pub fn collect() -> usize {
+    let mut nodes = vec![];
+    CANDIDATES.with(|c| {
+        c.borrow_mut().nodes.retain(|_, weak| match weak {
+            Weakref::Env(w) => w.upgrade().map(|e| nodes.push(Node::Env(e))).is_some(),
+            Weakref::Upvalue(w) => w.upgrade().map(|u| nodes.push(Node::Upvalue(u))).is_some(),
+        })
+    });
+    let mut index: HashMap<usize, usize> = nodes
+        .iter()
+        .enumerate()
+        .map(|(i, n)| (n.addr(), i))
+        .collect();
+
+    // Everything reachable, with the references between them
+    let mut edges: Vec<Vec<usize>> = vec![];
+    let mut pinned = vec![];
+    while edges.len() < nodes.len() {
+        let mut targets = vec![];
+        let mut found = vec![];
+        let traced = nodes[edges.len()].trace(&mut |n| {
+            let next = index.len();
+            match index.get(&n.addr()) {
+                Some(i) => targets.push(*i),
+                None => {
+                    index.insert(n.addr(), next);
+                    targets.push(next);
+                    found.push(n.to_node());
+                }
+            }
+        });
+        pinned.push(!traced);
+        edges.push(targets);
+        nodes.extend(found);
+    }
+
+    // References from outside the traced nodes, the one held by `nodes` aside
+    let mut refs: Vec<usize> = nodes.iter().map(|n| n.strong_count() - 1).collect();
+    for targets in &edges {
+        for &t in targets {
+            refs[t] = refs[t].saturating_sub(1);
+        }
+    }
+    let mut alive = vec![false; nodes.len()];
+    let mut stack: Vec<usize> = (0..nodes.len())
+        .filter(|&i| refs[i] > 0 || pinned[i])
+        .collect();
+    while let Some(i) = stack.pop() {
+        if !std::mem::replace(&mut alive[i], true) {
+            stack.extend(edges[i].iter().filter(|&&t| !alive[t]));
+        }
+    }
+
+    let collected = nodes
+        .iter()
+        .zip(&alive)
+        .filter(|(node, alive)| !**alive && node.clear())
+        .count();
+    STATS.with(|s| {
+        let stats = s.get();
+        s.set(Stats {
+            collections: stats.collections + 1,
+            collected: stats.collected + collected,
+            ..stats
+        })
+    });
+    collected
+}
+
+impl Node {
+    fn addr(&self) -> usize {
+        match self {
+            Node::Env(e) => NodeRef::Env(e).addr(),
+            Node::Upvalue(u) => NodeRef::Upvalue(u).addr(),
+            Node::Closure(c) => NodeRef::Closure(c).addr(),
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Node::Env(e) => Rc::strong_count(e),
+            Node::Upvalue(u) => Rc::strong_count(u),
+            Node::Closure(MalValue::Closure(c)) => Rc::strong_count(c),
+            Node::Closure(MalValue::Compiled(c)) => Rc::strong_count(c),
+            Node::Closure(MalValue::Code(c)) => Rc::strong_count(c),
+            Node::Closure(_) => usize::MAX,
+        }
+    }
+
+    /// Calls `f` on each node this one references, [false] if its values are borrowed
+    fn trace(&self, f: &mut dyn FnMut(NodeRef)) -> bool {
+        match self {
+            Node::Env(e) => {
+                let (Ok(data), Ok(slots)) = (e.data.try_borrow(), e.slots.try_borrow()) else {
+                    return false;
+                };
+                if let Some(parent) = &e.parent {
+                    f(NodeRef::Env(parent));
+                }
+                data.values()
+                    .chain(slots.iter())
+                    .for_each(|v| trace_value(v, f));
+                true
+            }
+            Node::Upvalue(u) => match u.try_borrow() {
+                Ok(v) => {
+                    trace_value(&v, f);
+                    true
+                }
+                Err(_) => false,
+            },
+            Node::Closure(MalValue::Closure(c)) => {
+                f(NodeRef::Env(&c.env));
+                true
+            }
+            Node::Closure(MalValue::Compiled(c)) => {
+                f(NodeRef::Env(&c.env));
+                c.upvalues.iter().for_each(|u| f(NodeRef::Upvalue(u)));
+                true
+            }
+            Node::Closure(MalValue::Code(c)) => {
+                f(NodeRef::Env(&c.env));
+                f(NodeRef::Env(&c.globals));
+                true
+            }
+            Node::Closure(_) => false,
+        }
+    }
+
+    /// Drops the values of a garbage node, breaking the cycles it is part of. [false] for the
+    /// closures, freed once their environment is cleared.
+    fn clear(&self) -> bool {
+        // Taken out first, dropping them may drop other nodes
+        match self {
+            Node::Env(e) => {
+                let data = std::mem::take(&mut *e.data.borrow_mut());
+                let slots = std::mem::take(&mut *e.slots.borrow_mut());
+                drop((data, slots));
+                true
+            }
+            Node::Upvalue(u) => {
+                drop(u.replace(MalValue::Nil));
+                true
+            }
+            Node::Closure(_) => false,
+        }
+    }
+}
+
+impl NodeRef<'_> {
+    fn addr(self) -> usize {
+        match self {
+            NodeRef::Env(e) => Rc::as_ptr(e) as usize,
+            NodeRef::Upvalue(u) => Rc::as_ptr(u) as usize,
+            NodeRef::Closure(MalValue::Closure(c)) => Rc::as_ptr(c) as usize,
+            NodeRef::Closure(MalValue::Compiled(c)) => Rc::as_ptr(c) as usize,
+            NodeRef::Closure(MalValue::Code(c)) => Rc::as_ptr(c) as usize,
+            NodeRef::Closure(v) => v as *const MalValue as usize,
+        }
+    }
+
+    fn to_node(self) -> Node {
+        match self {
+            NodeRef::Env(e) => Node::Env(e.clone()),
+            NodeRef::Upvalue(u) => Node::Upvalue(u.clone()),
+            NodeRef::Closure(c) => Node::Closure(c.clone()),
+        }
+    }
+}
+
+/// Calls `f` on the nodes referenced by a value. Only the collections held by nobody else are
+/// followed: one shared with the Rust stack or another value counts as an outside reference.
+fn trace_value(val: &MalValue, f: &mut dyn FnMut(NodeRef)) {
+    match val {
+        MalValue::Closure(_) | MalValue::Compiled(_) | MalValue::Code(_) => {
+            f(NodeRef::Closure(val))
+        }
+        MalValue::List(l) | MalValue::Vec(l) if Rc::strong_count(l) == 1 => {
+            l.iter().for_each(|v| trace_value(v, f))
+        }
+        MalValue::Map(m) if Rc::strong_count(m) == 1 => m.values().for_each(|v| trace_value(v, f)),
+        MalValue::SortedMap(m) if Rc::strong_count(m) == 1 => m
+            .entries
+            .values()
+            .chain(&m.by)
+            .for_each(|v| trace_value(v, f)),
+        _ => {}
+    }
+}
@@ -0,0 +1,84 @@
+//! What evaluation observes of the host: random numbers, the clock and the output.
+//!
+//! In deterministic mode the random numbers come from a seeded generator, the clock is virtual
+//! and the output is kept in memory until [take_output], so that running the same forms twice
+//! gives the same results and output.
+
+use std::{
+    cell::{Cell, RefCell},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Milliseconds the virtual clock advances on each reading
+const TICK_MS: i64 = 1;
+
+thread_local! {
+    static RNG: Cell<u64> = Cell::new(seed_from_clock());
+    /// Milliseconds since the start of the virtual clock, [None] for the real clock
+    static CLOCK: Cell<Option<i64>> = const { Cell::new(None) };
+    /// Output printed in deterministic mode
+    static OUTPUT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+fn seed_from_clock() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64)
+}
+
+/// Switches this thread to deterministic mode, the random numbers being generated from `seed`
+pub fn set_deterministic(seed: u64) {
+    RNG.with(|r| r.set(seed));
+    CLOCK.with(|c| c.set(Some(0)));
+    OUTPUT.with(|o| *o.borrow_mut() = Some(String::new()));
+}
+
+pub fn is_deterministic() -> bool {
+    CLOCK.with(|c| c.get().is_some())
+}
+
+/// Next pseudo-random number, from splitmix64
+pub fn random() -> u64 {
+    let state = RNG.with(|r| {
+        let state = r.get().wrapping_add(0x9e37_79b9_7f4a_7c15);
+        r.set(state);
+        state
+    });
+    let z = (state ^ (state >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    let z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Milliseconds since the Unix epoch, or since the start of the virtual clock
+pub fn now_ms() -> i64 {
+    CLOCK.with(|c| match c.get() {
+        Some(ms) => {
+            c.set(Some(ms + TICK_MS));
+            ms
+        }
+        None => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as i64),
+    })
+}
+
+/// Prints a line to stdout, or to the output kept in deterministic mode
+pub fn println(line: &str) {
+    OUTPUT.with(|o| match &mut *o.borrow_mut() {
+        Some(out) => {
+            out.push_str(line);
+            out.push('\n');
+        }
+        None => println!("{}", line),
+    })
+}
+
+/// Output printed since the last call in deterministic mode, empty otherwise
+pub fn take_output() -> String {
+    OUTPUT.with(|o| {
+        o.borrow_mut()
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    })
+}
@@ -1,7 +1,21 @@
-pub mod env;
 pub mod base_fn;
+pub mod budget;
+pub mod bytecode;
+pub mod closure_compiler;
+pub mod compiler;
+pub mod destructure;
+pub mod env;
+pub mod gc;
+pub mod host;
+pub mod malc;
+pub mod namespace;
+pub mod optimizer;
 pub mod printer;
 pub mod reader;
+pub mod resolver;
+pub mod sandbox;
+pub mod symbol;
 pub mod types;
+pub mod vm;
 
 pub type Result<T> = anyhow::Result<T>;
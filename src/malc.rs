@@ -0,0 +1,481 @@
+//! `.malc` cache of compiled source files, stored next to the source.
+//!
+//! The file starts with a header holding the id of the build of the interpreter and a hash of
+//! the source. A cache whose header doesn't match is ignored and rewritten.
+
+use std::{cell::RefCell, fs, path::Path, rc::Rc};
+
+use crate::{
+    bytecode::{Arity, Capture, Chunk, Op, Proto},
+    compiler::compile,
+    destructure::pattern_syms,
+    env::GlobalCache,
+    namespace,
+    printer::pr_str,
+    reader::read_all,
+    symbol::Symbol,
+    types::{MalMap, MalValue},
+    vm, Result,
+};
+use anyhow::{anyhow, Context};
+use regex::Regex;
+
+const MAGIC: &[u8; 4] = b"MALC";
+/// Hash of the sources of the library set by `build.rs`, it changes with the encoding, the
+/// compiler and the ops
+pub const BUILD_ID: &str = env!("MALC_BUILD_ID");
+
+/// Evaluates a form with an engine other than the VM
+pub type Evaluator = Rc<dyn Fn(&MalValue) -> Result<MalValue>>;
+
+thread_local! {
+    /// Engine of [load] on this thread, [None] for the VM and the cache
+    static EVALUATOR: RefCell<Option<Evaluator>> = const { RefCell::new(None) };
+}
+
+/// Makes [load] evaluate the forms with `eval` on this thread, without the cache
+pub fn set_evaluator(eval: Evaluator) {
+    EVALUATOR.with(|e| *e.borrow_mut() = Some(eval));
+}
+
+/// Runs the forms of a source file with the engine of this thread, see [set_evaluator], or else
+/// with [load_file]
+pub fn load(path: &Path) -> Result<MalValue> {
+    let Some(eval) = EVALUATOR.with(|e| e.borrow().clone()) else {
+        return load_file(path);
+    };
+    let source =
+        fs::read_to_string(path).with_context(|| anyhow!("Cannot read {}", path.display()))?;
+    for ast in read_all(&source)? {
+        eval(&ast)?;
+    }
+    Ok(MalValue::Nil)
+}
+
+/// Runs the forms of a source file in the current namespace, from its `.malc` cache when it is up
+/// to date. The cache is only written once the forms ran without error.
+pub fn load_file(path: &Path) -> Result<MalValue> {
+    let source =
+        fs::read_to_string(path).with_context(|| anyhow!("Cannot read {}", path.display()))?;
+    let cache = path.with_extension("malc");
+    let hash = source_hash(&source);
+    if let Some(protos) = fs::read(&cache)
+        .ok()
+        .and_then(|bytes| decode(&bytes, hash).ok())
+    {
+        return run(&protos);
+    }
+
+    let protos = read_all(&source)?
+        .iter()
+        .map(compile)
+        .collect::<Result<Vec<_>>>()?;
+    run(&protos)?;
+    // The cache is an optimisation, the file is loaded even when it can't be written
+    let written = encode(&protos, hash).and_then(|bytes| {
+        fs::write(&cache, bytes).with_context(|| anyhow!("Cannot write {}", cache.display()))
+    });
+    if let Err(e) = written {
+        eprintln!("Warning: {:#}", e);
+    }
+    Ok(MalValue::Nil)
+}
+
+fn run(protos: &[Rc<Proto>]) -> Result<MalValue> {
+    for proto in protos {
+        vm::run(proto.clone(), &namespace::current().env)?;
+    }
+    Ok(MalValue::Nil)
+}
+
+/// FNV-1a, stable across builds unlike the std hasher
+pub fn source_hash(source: &str) -> u64 {
+    source.bytes().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+pub fn encode(protos: &[Rc<Proto>], hash: u64) -> Result<Vec<u8>> {
+    let mut e = Encoder::default();
+    e.buf.extend(MAGIC);
+    e.str(BUILD_ID);
+    e.buf.extend(hash.to_le_bytes());
+    e.u32(protos.len() as u32);
+    for proto in protos {
+        e.proto(proto)?;
+    }
+    Ok(e.buf)
+}
+
+/// Decodes the cache, failing if it was made for another source or build of the interpreter
+pub fn decode(bytes: &[u8], hash: u64) -> Result<Vec<Rc<Proto>>> {
+    let mut d = Decoder { bytes, pos: 0 };
+    if d.take(4)? != MAGIC {
+        return Err(anyhow!("Not a .malc file"));
+    }
+    if d.str()? != BUILD_ID {
+        return Err(anyhow!("Cache made by another build of the interpreter"));
+    }
+    if d.u64()? != hash {
+        return Err(anyhow!("Cache made from another source"));
+    }
+    let protos = (0..d.u32()?)
+        .map(|_| d.proto())
+        .collect::<Result<Vec<_>>>()?;
+    for proto in &protos {
+        check(proto, None)?;
+    }
+    Ok(protos)
+}
+
+/// Checks the indices of the ops and captures against the tables of the proto, so that a corrupt
+/// cache is a miss rather than a panic of the VM. `outer` is the arity creating the closures of
+/// the proto, with the number of upvalues of its own proto, none for a top level form.
+fn check(proto: &Proto, outer: Option<(&Arity, usize)>) -> Result<()> {
+    let index = |i: u32, len: usize, what: &str| match (i as usize) < len {
+        true => Ok(()),
+        false => Err(anyhow!("Invalid {} index {} in .malc file", what, i)),
+    };
+    for capture in &proto.captures {
+        match (capture, outer) {
+            (Capture::Local(i), Some((arity, _))) => index(*i, arity.slots as usize, "local")?,
+            (Capture::Upvalue(i), Some((_, upvalues))) => index(*i, upvalues, "upvalue")?,
+            (_, None) => return Err(anyhow!("Capture of a top level form in .malc file")),
+        }
+    }
+    for arity in &proto.arities {
+        let chunk = &arity.chunk;
+        let constant = |i| index(i, chunk.constants.len(), "constant");
+        let local = |i| index(i, arity.slots as usize, "local");
+        if chunk.code.last() != Some(&Op::Return) {
+            return Err(anyhow!("Chunk not ending with a return in .malc file"));
+        }
+        for op in &chunk.code {
+            match *op {
+                Op::Const(i) | Op::Map(i) | Op::Ns(i) => constant(i)?,
+                Op::GetGlobal { cache, .. } => index(cache, chunk.caches.len(), "cache")?,
+                Op::GetLocal(i) | Op::SetLocal(i) | Op::ClearLocal(i) => local(i)?,
+                Op::GetUpvalue(i) => index(i, proto.captures.len(), "upvalue")?,
+                Op::Bind { pattern, slot } => {
+                    constant(pattern)?;
+                    let mut syms = vec![];
+                    pattern_syms(&chunk.constants[pattern as usize], &mut syms)?;
+                    if slot as usize + syms.len() > arity.slots as usize {
+                        return Err(anyhow!("Invalid local index {} in .malc file", slot));
+                    }
+                }
+                Op::Jump(i) | Op::JumpIfFalse(i) => index(i, chunk.code.len(), "jump")?,
+                Op::Closure(i) => index(i, chunk.protos.len(), "proto")?,
+                _ => {}
+            }
+        }
+        for inner in &chunk.protos {
+            check(inner, Some((arity, proto.captures.len())))?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    fn u32(&mut self, n: u32) {
+        self.buf.extend(n.to_le_bytes());
+    }
+
+    fn str(&mut self, s: &str) {
+        self.u32(s.len() as u32);
+        self.buf.extend(s.as_bytes());
+    }
+
+    fn sym(&mut self, sym: Symbol) {
+        self.str(sym.name());
+    }
+
+    fn value(&mut self, val: &MalValue) -> Result<()> {
+        match val {
+            MalValue::Nil => self.buf.push(0),
+            MalValue::True => self.buf.push(1),
+            MalValue::False => self.buf.push(2),
+            MalValue::Number(n) => {
+                self.buf.push(3);
+                self.buf.extend(n.to_le_bytes());
+            }
+            MalValue::Sym(sym) => {
+                self.buf.push(4);
+                self.sym(*sym);
+            }
+            MalValue::Atom(sym) => {
+                self.buf.push(5);
+                self.sym(*sym);
+            }
+            MalValue::String(s) => {
+                self.buf.push(6);
+                self.str(s);
+            }
+            MalValue::List(l) | MalValue::Vec(l) => {
+                self.buf
+                    .push(if let MalValue::List(_) = val { 7 } else { 8 });
+                self.u32(l.len() as u32);
+                for v in l.iter() {
+                    self.value(v)?;
+                }
+            }
+            MalValue::Map(map) => {
+                self.buf.push(9);
+                self.u32(map.len() as u32);
+                for (k, v) in map.iter() {
+                    self.str(k);
+                    self.value(v)?;
+                }
+            }
+            MalValue::Regex(re) => {
+                self.buf.push(10);
+                self.str(re.as_str());
+            }
+            v => return Err(anyhow!("Cannot cache the value {}", pr_str(v))),
+        }
+        Ok(())
+    }
+
+    fn op(&mut self, op: Op) {
+        let (tag, operands): (u8, &[u32]) = match op {
+            Op::Const(i) => (0, &[i]),
+            Op::Nil => (1, &[]),
+            Op::True => (2, &[]),
+            Op::False => (3, &[]),
+            Op::GetGlobal { sym, cache } => {
+                self.buf.push(4);
+                self.sym(sym);
+                self.u32(cache);
+                return;
+            }
+            Op::DefGlobal(sym) | Op::DefPrivate(sym) | Op::InNs(sym) => {
+                self.buf.push(match op {
+                    Op::DefGlobal(_) => 5,
+                    Op::DefPrivate(_) => 6,
+                    _ => 20,
+                });
+                self.sym(sym);
+                return;
+            }
+            Op::GetLocal(i) => (7, &[i]),
+            Op::SetLocal(i) => (8, &[i]),
+            Op::ClearLocal(i) => (9, &[i]),
+            Op::GetUpvalue(i) => (10, &[i]),
+            Op::Bind { pattern, slot } => (11, &[pattern, slot]),
+            Op::Pop => (12, &[]),
+            Op::Jump(i) => (13, &[i]),
+            Op::JumpIfFalse(i) => (14, &[i]),
+            Op::Call(i) => (15, &[i]),
+            Op::Return => (16, &[]),
+            Op::Closure(i) => (17, &[i]),
+            Op::Vec(i) => (18, &[i]),
+            Op::Map(i) => (19, &[i]),
+            Op::Ns(i) => (21, &[i]),
+        };
+        self.buf.push(tag);
+        for n in operands {
+            self.u32(*n);
+        }
+    }
+
+    fn chunk(&mut self, chunk: &Chunk) -> Result<()> {
+        self.u32(chunk.code.len() as u32);
+        for op in &chunk.code {
+            self.op(*op);
+        }
+        self.u32(chunk.constants.len() as u32);
+        for val in &chunk.constants {
+            self.value(val)?;
+        }
+        self.u32(chunk.protos.len() as u32);
+        for proto in &chunk.protos {
+            self.proto(proto)?;
+        }
+        self.u32(chunk.caches.len() as u32);
+        Ok(())
+    }
+
+    fn proto(&mut self, proto: &Proto) -> Result<()> {
+        match proto.name {
+            Some(name) => {
+                self.buf.push(1);
+                self.sym(name);
+            }
+            None => self.buf.push(0),
+        }
+        self.u32(proto.captures.len() as u32);
+        for capture in &proto.captures {
+            let (tag, i) = match capture {
+                Capture::Local(i) => (0, i),
+                Capture::Upvalue(i) => (1, i),
+            };
+            self.buf.push(tag);
+            self.u32(*i);
+        }
+        self.u32(proto.arities.len() as u32);
+        for arity in &proto.arities {
+            self.value(&arity.params)?;
+            self.u32(arity.fixed as u32);
+            self.buf.push(arity.variadic as u8);
+            self.u32(arity.slots);
+            self.chunk(&arity.chunk)?;
+        }
+        self.u32(proto.source.len() as u32);
+        for (params, body) in &proto.source {
+            self.value(params)?;
+            self.value(body)?;
+        }
+        Ok(())
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Decoder<'_> {
+    fn take(&mut self, n: usize) -> Result<&[u8]> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos + n)
+            .context("Truncated .malc file")?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn str(&mut self) -> Result<String> {
+        let len = self.u32()? as usize;
+        Ok(std::str::from_utf8(self.take(len)?)?.to_string())
+    }
+
+    fn sym(&mut self) -> Result<Symbol> {
+        Ok(Symbol::new(&self.str()?))
+    }
+
+    fn value(&mut self) -> Result<MalValue> {
+        Ok(match self.u8()? {
+            0 => MalValue::Nil,
+            1 => MalValue::True,
+            2 => MalValue::False,
+            3 => MalValue::Number(i64::from_le_bytes(self.take(8)?.try_into()?)),
+            4 => MalValue::Sym(self.sym()?),
+            5 => MalValue::Atom(self.sym()?),
+            6 => MalValue::String(self.str()?.into()),
+            tag @ (7 | 8) => {
+                let items = (0..self.u32()?)
+                    .map(|_| self.value())
+                    .collect::<Result<Vec<_>>>()?;
+                match tag {
+                    7 => MalValue::List(Rc::new(items)),
+                    _ => MalValue::Vec(Rc::new(items)),
+                }
+            }
+            9 => {
+                let map = (0..self.u32()?)
+                    .map(|_| Ok((self.str()?, self.value()?)))
+                    .collect::<Result<MalMap>>()?;
+                MalValue::Map(Rc::new(map))
+            }
+            10 => MalValue::Regex(Rc::new(Regex::new(&self.str()?)?)),
+            tag => return Err(anyhow!("Unknown value tag {}", tag)),
+        })
+    }
+
+    fn op(&mut self) -> Result<Op> {
+        Ok(match self.u8()? {
+            0 => Op::Const(self.u32()?),
+            1 => Op::Nil,
+            2 => Op::True,
+            3 => Op::False,
+            4 => Op::GetGlobal {
+                sym: self.sym()?,
+                cache: self.u32()?,
+            },
+            5 => Op::DefGlobal(self.sym()?),
+            6 => Op::DefPrivate(self.sym()?),
+            7 => Op::GetLocal(self.u32()?),
+            8 => Op::SetLocal(self.u32()?),
+            9 => Op::ClearLocal(self.u32()?),
+            10 => Op::GetUpvalue(self.u32()?),
+            11 => Op::Bind {
+                pattern: self.u32()?,
+                slot: self.u32()?,
+            },
+            12 => Op::Pop,
+            13 => Op::Jump(self.u32()?),
+            14 => Op::JumpIfFalse(self.u32()?),
+            15 => Op::Call(self.u32()?),
+            16 => Op::Return,
+            17 => Op::Closure(self.u32()?),
+            18 => Op::Vec(self.u32()?),
+            19 => Op::Map(self.u32()?),
+            20 => Op::InNs(self.sym()?),
+            21 => Op::Ns(self.u32()?),
+            tag => return Err(anyhow!("Unknown op tag {}", tag)),
+        })
+    }
+
+    fn chunk(&mut self) -> Result<Chunk> {
+        Ok(Chunk {
+            code: (0..self.u32()?).map(|_| self.op()).collect::<Result<_>>()?,
+            constants: (0..self.u32()?)
+                .map(|_| self.value())
+                .collect::<Result<_>>()?,
+            protos: (0..self.u32()?)
+                .map(|_| self.proto())
+                .collect::<Result<_>>()?,
+            caches: (0..self.u32()?).map(|_| GlobalCache::default()).collect(),
+        })
+    }
+
+    fn proto(&mut self) -> Result<Rc<Proto>> {
+        let name = match self.u8()? {
+            0 => None,
+            _ => Some(self.sym()?),
+        };
+        let captures = (0..self.u32()?)
+            .map(|_| match (self.u8()?, self.u32()?) {
+                (0, i) => Ok(Capture::Local(i)),
+                (_, i) => Ok(Capture::Upvalue(i)),
+            })
+            .collect::<Result<_>>()?;
+        let arities = (0..self.u32()?)
+            .map(|_| {
+                Ok(Arity {
+                    params: self.value()?,
+                    fixed: self.u32()? as usize,
+                    variadic: self.u8()? != 0,
+                    slots: self.u32()?,
+                    chunk: self.chunk()?,
+                })
+            })
+            .collect::<Result<_>>()?;
+        let source = (0..self.u32()?)
+            .map(|_| Ok((self.value()?, self.value()?)))
+            .collect::<Result<_>>()?;
+        Ok(Rc::new(Proto {
+            name,
+            captures,
+            arities,
+            source,
+        }))
+    }
+}
@@ -0,0 +1,242 @@
+use crate::{
+    env::{bump_version, Env},
+    printer::pr_str,
+    sandbox::{self, Capability},
+    symbol::{self, Symbol, SymbolMap},
+    types::MalValue,
+    Result,
+};
+use anyhow::anyhow;
+use std::{cell::RefCell, rc::Rc};
+
+/// Namespace holding the builtins, every other namespace is built on top of it
+pub const CORE_NS: &str = "mal.core";
+/// Namespace the REPL starts in
+pub const USER_NS: &str = "user";
+
+#[derive(Debug)]
+pub struct Namespace {
+    pub name: Symbol,
+    pub env: Rc<Env>,
+    aliases: RefCell<SymbolMap<Symbol>>,
+    refers: RefCell<SymbolMap<Symbol>>,
+    private: RefCell<SymbolMap<()>>,
+}
+
+thread_local! {
+    static NAMESPACES: RefCell<SymbolMap<Rc<Namespace>>> = RefCell::default();
+    static CURRENT: RefCell<Symbol> = RefCell::new(Symbol::new(USER_NS));
+}
+
+impl Namespace {
+    fn new(name: Symbol, env: Rc<Env>) -> Self {
+        Self {
+            name,
+            env,
+            aliases: RefCell::default(),
+            refers: RefCell::default(),
+            private: RefCell::default(),
+        }
+    }
+
+    /// Makes `alias/sym` resolve to `target/sym` in this namespace
+    pub fn add_alias(&self, alias: Symbol, target: Symbol) -> Result<()> {
+        find(target).ok_or_else(|| anyhow!("Cannot alias unknown namespace: {}", target))?;
+        self.aliases.borrow_mut().insert(alias, target);
+        bump_version(&self.env);
+        Ok(())
+    }
+
+    /// Makes the unqualified `sym` resolve to `target/sym` in this namespace
+    pub fn refer(&self, target: Symbol, sym: Symbol) -> Result<()> {
+        let ns =
+            find(target).ok_or_else(|| anyhow!("Cannot refer unknown namespace: {}", target))?;
+        if !ns.env.contains_sym(sym) {
+            return Err(anyhow!("Cannot refer '{}', not found in {}", sym, target));
+        }
+        if ns.is_private(sym) {
+            return Err(anyhow!("Cannot refer '{}', private in {}", sym, target));
+        }
+        self.refers.borrow_mut().insert(sym, target);
+        bump_version(&self.env);
+        Ok(())
+    }
+
+    /// Refers every public symbol of `target` in this namespace
+    pub fn refer_all(&self, target: Symbol) -> Result<()> {
+        let ns =
+            find(target).ok_or_else(|| anyhow!("Cannot refer unknown namespace: {}", target))?;
+        for sym in ns.public_syms() {
+            self.refers.borrow_mut().insert(sym, target);
+        }
+        bump_version(&self.env);
+        Ok(())
+    }
+
+    pub fn set_private(&self, sym: Symbol) {
+        self.private.borrow_mut().insert(sym, ());
+        bump_version(&self.env);
+    }
+
+    pub fn is_private(&self, sym: Symbol) -> bool {
+        self.private.borrow().contains_key(&sym)
+    }
+
+    fn public_syms(&self) -> Vec<Symbol> {
+        self.env
+            .syms()
+            .into_iter()
+            .filter(|s| !self.is_private(*s))
+            .collect()
+    }
+
+    fn resolve_alias(&self, ns: Symbol) -> Symbol {
+        self.aliases.borrow().get(&ns).copied().unwrap_or(ns)
+    }
+
+    /// Applies a require spec such as `[other :as o :refer [a b]]` to this namespace
+    pub fn require(&self, spec: &MalValue) -> Result<()> {
+        let (target, opts) = match spec {
+            MalValue::Sym(target) => (*target, &[][..]),
+            MalValue::Vec(v) | MalValue::List(v) => match v.split_first() {
+                Some((MalValue::Sym(target), opts)) => (*target, opts),
+                _ => return Err(anyhow!("Invalid require spec: {}", pr_str(spec))),
+            },
+            v => return Err(anyhow!("Invalid require spec: {}", pr_str(v))),
+        };
+        find(target).ok_or_else(|| anyhow!("Cannot require unknown namespace: {}", target))?;
+
+        if !opts.len().is_multiple_of(2) {
+            return Err(anyhow!("Invalid require options: {}", pr_str(spec)));
+        }
+        for opt in opts.chunks(2) {
+            match (&opt[0], &opt[1]) {
+                (MalValue::Atom(symbol::AS), MalValue::Sym(alias)) => {
+                    self.add_alias(*alias, target)?
+                }
+                (MalValue::Atom(symbol::REFER), MalValue::Atom(symbol::ALL)) => {
+                    self.refer_all(target)?
+                }
+                (MalValue::Atom(symbol::REFER), MalValue::Vec(syms) | MalValue::List(syms)) => {
+                    for sym in syms.iter() {
+                        match sym {
+                            MalValue::Sym(sym) => self.refer(target, *sym)?,
+                            v => return Err(anyhow!("Cannot refer non symbol: {}", pr_str(v))),
+                        }
+                    }
+                }
+                (k, v) => {
+                    return Err(anyhow!(
+                        "Unknown require option: {} {}",
+                        pr_str(k),
+                        pr_str(v)
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Registers the core environment holding the builtins and creates the user namespace.
+/// Returns the environment of the user namespace.
+pub fn init(core: Rc<Env>) -> Rc<Env> {
+    let name = Symbol::new(CORE_NS);
+    NAMESPACES.with(|n| {
+        n.borrow_mut()
+            .insert(name, Rc::new(Namespace::new(name, core)))
+    });
+    in_ns(Symbol::new(USER_NS)).env.clone()
+}
+
+pub fn find(name: Symbol) -> Option<Rc<Namespace>> {
+    NAMESPACES.with(|n| n.borrow().get(&name).cloned())
+}
+
+/// Finds the namespace `name` or creates it on top of the core namespace
+pub fn find_or_create(name: Symbol) -> Rc<Namespace> {
+    if let Some(ns) = find(name) {
+        return ns;
+    }
+    let core = match find(Symbol::new(CORE_NS)) {
+        Some(core) => core.env.clone(),
+        None => Rc::new(Env::new()),
+    };
+    let ns = Rc::new(Namespace::new(name, Rc::new(Env::new_ns(core, name))));
+    NAMESPACES.with(|n| n.borrow_mut().insert(name, ns.clone()));
+    ns
+}
+
+pub fn current() -> Rc<Namespace> {
+    find_or_create(CURRENT.with(|c| *c.borrow()))
+}
+
+/// Switches the current namespace to `name`, creating it if needed
+pub fn in_ns(name: Symbol) -> Rc<Namespace> {
+    let ns = find_or_create(name);
+    CURRENT.with(|c| *c.borrow_mut() = name);
+    ns
+}
+
+/// Evaluates the arguments of a `(ns name (:require specs...))` form, switching to the namespace
+pub fn ns_form(form: &[MalValue]) -> Result<Rc<Namespace>> {
+    sandbox::check(Capability::Namespaces)?;
+    let ns = match form.first() {
+        Some(MalValue::Sym(name)) => in_ns(*name),
+        _ => return Err(anyhow!("ns needs a namespace name")),
+    };
+    for clause in &form[1..] {
+        match clause {
+            MalValue::List(l) if l.first() == Some(&MalValue::Atom(symbol::REQUIRE)) => {
+                for spec in &l[1..] {
+                    ns.require(spec)?;
+                }
+            }
+            v => return Err(anyhow!("Invalid ns clause: {}", pr_str(v))),
+        }
+    }
+    Ok(ns)
+}
+
+/// Marks the symbol defined in `env` as private to its namespace
+pub fn set_private(env: &Env, sym: &MalValue) {
+    if let (Some(ns), MalValue::Sym(sym)) = (owner(env), sym) {
+        ns.set_private(*sym);
+    }
+}
+
+/// Namespace owning the environment, the core namespace if none.
+/// Sandboxed code is not in any namespace.
+pub fn owner(env: &Env) -> Option<Rc<Namespace>> {
+    if sandbox::is_active() {
+        return None;
+    }
+    find(env.ns_name().unwrap_or_else(|| Symbol::new(CORE_NS)))
+}
+
+/// Resolves `ns/name`, where `ns` is either an alias of the namespace owning `env` or a full
+/// namespace name.
+/// Private symbols can only be resolved from their own namespace.
+pub fn resolve_qualified(env: &Env, ns: Symbol, name: Symbol) -> Option<MalValue> {
+    if sandbox::is_active() {
+        return None;
+    }
+    let from = owner(env);
+    let target = match &from {
+        Some(from) => from.resolve_alias(ns),
+        None => ns,
+    };
+    let target = find(target)?;
+    let same_ns = from.is_some_and(|f| f.name == target.name);
+    if target.is_private(name) && !same_ns {
+        return None;
+    }
+    target.env.get_own_sym(name)
+}
+
+/// Resolves a symbol referred in the namespace owning `env`
+pub fn resolve_referred(env: &Env, sym: Symbol) -> Option<MalValue> {
+    let from = owner(env)?;
+    let target = from.refers.borrow().get(&sym).copied()?;
+    resolve_qualified(env, target, sym)
+}
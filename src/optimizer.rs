@@ -0,0 +1,349 @@
+use std::rc::Rc;
+
+use crate::{
+    destructure::pattern_syms,
+    env::{env_get_sym, Env},
+    namespace,
+    resolver::{expand_defn, fn_arities},
+    symbol::{self, Symbol},
+    types::MalValue,
+};
+
+/// Builtins without side effects, folded when called on literal numbers
+const FOLDABLE: [&str; 18] = [
+    "+", "-", "*", "/", "=", "<", "<=", ">", ">=", "inc", "dec", "mod", "rem", "quot", "abs",
+    "min", "max", "pow",
+];
+
+/// Rewrites a form before it is evaluated: folds the arithmetic on literals when the builtins
+/// can't be redefined before it runs, outside of the `fn*` bodies, removes the `if`
+/// branches that can't be taken, inlines the `let*` bindings to literals and flattens nested `do`.
+/// Forms that can't be parsed are left as they are, for eval to report the error.
+pub struct Optimizer<'a> {
+    env: &'a Rc<Env>,
+    /// Locals in scope, shadowing the builtins
+    locals: Vec<Symbol>,
+    /// Whether the builtins are still the ones of `env` when the code being optimised runs
+    folds: bool,
+}
+
+/// Optimises a top level form, evaluated in `env`
+pub fn optimize(env: &Rc<Env>, ast: &MalValue) -> MalValue {
+    let mut optimizer = Optimizer {
+        env,
+        locals: vec![],
+        folds: !may_redefine(ast),
+    };
+    optimizer.optimize(ast)
+}
+
+/// Whether the builtins may be redefined while the form runs: it calls something else than the
+/// special forms and the foldable builtins, any function possibly calling `def!`, or it names a
+/// foldable builtin elsewhere than in a call, to define it, bind it or pass it around
+fn may_redefine(ast: &MalValue) -> bool {
+    let special = [
+        symbol::DEF,
+        symbol::DEF_PRIVATE,
+        symbol::LET,
+        symbol::DO,
+        symbol::IF,
+        symbol::FN,
+        symbol::DEFN,
+        symbol::DEFN_PRIVATE,
+    ];
+    match ast {
+        MalValue::Sym(sym) => FOLDABLE.contains(&sym.name()),
+        MalValue::List(l) => match l.split_first() {
+            Some((MalValue::Sym(head), args)) => {
+                !(special.contains(head) || FOLDABLE.contains(&head.name()))
+                    || args.iter().any(may_redefine)
+            }
+            Some(_) => true,
+            None => false,
+        },
+        MalValue::Vec(v) => v.iter().any(may_redefine),
+        MalValue::Map(m) => m.values().any(may_redefine),
+        _ => false,
+    }
+}
+
+fn is_literal(val: &MalValue) -> bool {
+    matches!(
+        val,
+        MalValue::Nil
+            | MalValue::True
+            | MalValue::False
+            | MalValue::Number(_)
+            | MalValue::String(_)
+            | MalValue::Atom(_)
+    )
+}
+
+fn list(items: Vec<MalValue>) -> MalValue {
+    MalValue::List(Rc::new(items))
+}
+
+impl Optimizer<'_> {
+    pub fn optimize(&mut self, ast: &MalValue) -> MalValue {
+        match ast {
+            MalValue::List(l) if !l.is_empty() => {
+                self.optimize_list(l).unwrap_or_else(|| ast.clone())
+            }
+            MalValue::Vec(v) => MalValue::Vec(Rc::new(self.optimize_all(v))),
+            MalValue::Map(m) => MalValue::Map(Rc::new(
+                m.iter()
+                    .map(|(k, v)| (k.clone(), self.optimize(v)))
+                    .collect(),
+            )),
+            v => v.clone(),
+        }
+    }
+
+    fn optimize_all(&mut self, forms: &[MalValue]) -> Vec<MalValue> {
+        forms.iter().map(|v| self.optimize(v)).collect()
+    }
+
+    /// Optimised list, none when it is malformed
+    fn optimize_list(&mut self, l: &[MalValue]) -> Option<MalValue> {
+        let tail = &l[1..];
+        match &l[0] {
+            MalValue::Sym(symbol::DEF | symbol::DEF_PRIVATE) => match tail {
+                [name, val] => Some(list(vec![l[0].clone(), name.clone(), self.optimize(val)])),
+                _ => None,
+            },
+            MalValue::Sym(symbol::NS | symbol::IN_NS) => None,
+            MalValue::Sym(symbol::DEFN | symbol::DEFN_PRIVATE) => {
+                Some(self.optimize(&expand_defn(l).ok()?))
+            }
+            MalValue::Sym(symbol::DO) => {
+                let mut forms = vec![];
+                for form in self.optimize_all(tail) {
+                    match form {
+                        MalValue::List(l) if l.first() == Some(&MalValue::Sym(symbol::DO)) => {
+                            forms.extend(l[1..].iter().cloned())
+                        }
+                        form => forms.push(form),
+                    }
+                }
+                // Literals before the last form have no effect
+                let last = forms.pop();
+                forms.retain(|f| !is_literal(f));
+                forms.extend(last);
+                Some(match forms.len() {
+                    0 => MalValue::Nil,
+                    1 => forms.remove(0),
+                    _ => list([vec![l[0].clone()], forms].concat()),
+                })
+            }
+            MalValue::Sym(symbol::IF) => {
+                if tail.len() < 2 || tail.len() > 3 {
+                    return None;
+                }
+                let cond = self.optimize(&tail[0]);
+                let otherwise = tail.get(2).unwrap_or(&MalValue::Nil);
+                Some(match cond {
+                    MalValue::Nil | MalValue::False => self.optimize(otherwise),
+                    c if is_literal(&c) => self.optimize(&tail[1]),
+                    c => {
+                        let mut form = vec![l[0].clone(), c];
+                        form.extend(self.optimize_all(&tail[1..]));
+                        list(form)
+                    }
+                })
+            }
+            MalValue::Sym(symbol::LET) => match tail {
+                [MalValue::Vec(b) | MalValue::List(b), body] if b.len().is_multiple_of(2) => {
+                    self.optimize_let(b, body)
+                }
+                _ => None,
+            },
+            MalValue::Sym(symbol::FN) => self.optimize_fn(tail),
+            _ => {
+                let form = self.optimize_all(l);
+                Some(self.fold(&form).unwrap_or_else(|| list(form)))
+            }
+        }
+    }
+
+    fn optimize_fn(&mut self, form: &[MalValue]) -> Option<MalValue> {
+        let (name, arities) = fn_arities(form).ok()?;
+        let mut optimized = vec![];
+        for (params, body) in arities {
+            let mut names = Vec::from_iter(name);
+            pattern_syms(&params, &mut names).ok()?;
+            let scope = self.locals.len();
+            self.locals.extend(names);
+            // The body runs later, once anything may have been redefined
+            let folds = std::mem::replace(&mut self.folds, false);
+            let body = self.optimize(&body);
+            self.folds = folds;
+            self.locals.truncate(scope);
+            optimized.push((params, body));
+        }
+
+        let mut fn_form = vec![MalValue::Sym(symbol::FN)];
+        fn_form.extend(name.map(MalValue::Sym));
+        match optimized.as_slice() {
+            [(params, body)] => fn_form.extend([params.clone(), body.clone()]),
+            _ => fn_form.extend(optimized.into_iter().map(|(p, b)| list(vec![p, b]))),
+        }
+        Some(list(fn_form))
+    }
+
+    fn optimize_let(&mut self, bindings: &[MalValue], body: &MalValue) -> Option<MalValue> {
+        let mut names = vec![];
+        for pattern in bindings.iter().step_by(2) {
+            pattern_syms(pattern, &mut names).ok()?;
+        }
+        let scope = self.locals.len();
+        self.locals.extend(&names);
+
+        let mut pairs: Vec<(MalValue, MalValue)> = bindings
+            .chunks(2)
+            .map(|b| (b[0].clone(), self.optimize(&b[1])))
+            .collect();
+        let mut body = body.clone();
+        let mut i = 0;
+        while i < pairs.len() {
+            if let Some((rest, new_body)) = self.inline(&pairs, i, &names, &body) {
+                pairs.remove(i);
+                pairs.splice(i.., rest);
+                for pair in &mut pairs[i..] {
+                    pair.1 = self.optimize(&pair.1);
+                }
+                body = new_body;
+            } else {
+                i += 1;
+            }
+        }
+        let body = self.optimize(&body);
+        self.locals.truncate(scope);
+
+        if pairs.is_empty() {
+            return Some(body);
+        }
+        let bindings = pairs.into_iter().flat_map(|(k, v)| [k, v]).collect();
+        Some(list(vec![
+            MalValue::Sym(symbol::LET),
+            MalValue::Vec(Rc::new(bindings)),
+            body,
+        ]))
+    }
+
+    /// Substitutes the binding `i` of a `let*` in the following bindings and the body,
+    /// when it binds a symbol to a literal that is bound once and not used before
+    fn inline(
+        &self,
+        pairs: &[(MalValue, MalValue)],
+        i: usize,
+        names: &[Symbol],
+        body: &MalValue,
+    ) -> Option<(Vec<(MalValue, MalValue)>, MalValue)> {
+        let (MalValue::Sym(sym), val) = &pairs[i] else {
+            return None;
+        };
+        if !is_literal(val)
+            || *sym == symbol::AMPERSAND
+            || names.iter().filter(|n| *n == sym).count() > 1
+            || pairs[..i]
+                .iter()
+                .any(|(k, v)| mentions(k, *sym) || mentions(v, *sym))
+        {
+            return None;
+        }
+        // The `:or` defaults of the patterns are forms too
+        let rest = pairs[i + 1..]
+            .iter()
+            .map(|(k, v)| Some((substitute(k, *sym, val)?, substitute(v, *sym, val)?)))
+            .collect::<Option<_>>()?;
+        Some((rest, substitute(body, *sym, val)?))
+    }
+
+    /// Calls a foldable builtin on literal numbers, unless it is shadowed or would fail
+    fn fold(&self, form: &[MalValue]) -> Option<MalValue> {
+        let (MalValue::Sym(sym), args) = form.split_first()? else {
+            return None;
+        };
+        if !self.folds || !FOLDABLE.contains(&sym.name()) || self.locals.contains(sym) {
+            return None;
+        }
+        let builtin = namespace::find(Symbol::new(namespace::CORE_NS))?
+            .env
+            .get_own_sym(*sym)?;
+        if env_get_sym(self.env, *sym).as_ref() != Some(&builtin) {
+            return None;
+        }
+
+        if !args.iter().all(|a| matches!(a, MalValue::Number(_))) {
+            return None;
+        }
+        match builtin {
+            MalValue::Function(f) => f(args).ok(),
+            _ => None,
+        }
+    }
+}
+
+/// Whether the symbol appears anywhere in the form
+fn mentions(ast: &MalValue, sym: Symbol) -> bool {
+    match ast {
+        MalValue::Sym(s) => *s == sym,
+        MalValue::List(l) | MalValue::Vec(l) => l.iter().any(|v| mentions(v, sym)),
+        MalValue::Map(m) => m.values().any(|v| mentions(v, sym)),
+        _ => false,
+    }
+}
+
+/// Replaces the symbol by `val` in the form, stopping at the `fn*` binding it again.
+/// None when a nested `let*` binds it again, its letrec scoping being left to eval.
+fn substitute(ast: &MalValue, sym: Symbol, val: &MalValue) -> Option<MalValue> {
+    Some(match ast {
+        MalValue::Sym(s) if *s == sym => val.clone(),
+        MalValue::List(l) => match l.as_slice() {
+            [MalValue::Sym(symbol::FN), form @ ..] => {
+                let (name, arities) = fn_arities(form).ok()?;
+                let mut names = Vec::from_iter(name);
+                for (params, _) in &arities {
+                    pattern_syms(params, &mut names).ok()?;
+                }
+                if names.contains(&sym) {
+                    return Some(ast.clone());
+                }
+                list(substitute_all(l, sym, val)?)
+            }
+            [MalValue::Sym(symbol::LET), bindings, ..] => {
+                let mut names = vec![];
+                if let MalValue::Vec(b) | MalValue::List(b) = bindings {
+                    for pattern in b.iter().step_by(2) {
+                        pattern_syms(pattern, &mut names).ok()?;
+                    }
+                }
+                if names.contains(&sym) {
+                    return None;
+                }
+                list(substitute_all(l, sym, val)?)
+            }
+            [MalValue::Sym(symbol::DEFN | symbol::DEFN_PRIVATE), ..] => {
+                substitute(&expand_defn(l).ok()?, sym, val)?
+            }
+            [def @ MalValue::Sym(symbol::DEF | symbol::DEF_PRIVATE), name, rest @ ..] => {
+                let mut form = vec![def.clone(), name.clone()];
+                form.extend(substitute_all(rest, sym, val)?);
+                list(form)
+            }
+            [MalValue::Sym(symbol::NS | symbol::IN_NS), ..] => ast.clone(),
+            _ => list(substitute_all(l, sym, val)?),
+        },
+        MalValue::Vec(v) => MalValue::Vec(Rc::new(substitute_all(v, sym, val)?)),
+        MalValue::Map(m) => MalValue::Map(Rc::new(
+            m.iter()
+                .map(|(k, v)| Some((k.clone(), substitute(v, sym, val)?)))
+                .collect::<Option<_>>()?,
+        )),
+        v => v.clone(),
+    })
+}
+
+fn substitute_all(forms: &[MalValue], sym: Symbol, val: &MalValue) -> Option<Vec<MalValue>> {
+    forms.iter().map(|v| substitute(v, sym, val)).collect()
+}
@@ -1,6 +1,9 @@
 use std::rc::Rc;
 
-use crate::types::MalValue;
+use crate::{
+    base_fn::BUILTINS,
+    types::{key_value, MalValue},
+};
 
 pub fn pr_str(val: &MalValue) -> String {
     match val {
@@ -8,34 +11,90 @@ pub fn pr_str(val: &MalValue) -> String {
         MalValue::True => "true".into(),
         MalValue::False => "false".into(),
         MalValue::Number(num) => num.to_string(),
-        MalValue::Sym(sym) => sym.clone(),
+        MalValue::Sym(sym) => sym.to_string(),
         MalValue::String(val) => format!("\"{}\"", val),
         MalValue::Atom(val) => format!(":{}", val),
         MalValue::List(list) => pr_seq(list.clone(), '(', ')'),
         MalValue::Vec(list) => pr_seq(list.clone(), '[', ']'),
-        MalValue::Map(map) => {
-            let val: Vec<_> = map
-                .iter()
+        MalValue::Map(_) | MalValue::SortedMap(_) => {
+            let val: Vec<_> = val
+                .map_entries()
+                .into_iter()
+                .flatten()
                 .map(|(k, v)| format!("{} {}", k, pr_str(v)))
                 .collect();
             format!("{{{}}}", val.join(" "))
         }
-        MalValue::Function(fun) => format!("<fn {:?}>", fun),
-        MalValue::Closure {
-            func,
-            env: _,
-            ast,
-            params,
-        } => format!(
-            "<closure {:?}: {}, {}>",
-            func,
-            pr_str(&ast),
-            pr_str(&params)
-        ),
+        MalValue::Regex(re) => format!("#\"{}\"", re.as_str()),
+        MalValue::Function(fun) => {
+            match BUILTINS.iter().find(|b| std::ptr::fn_addr_eq(b.func, *fun)) {
+                Some(builtin) => format!("<fn {}>", builtin.name),
+                None => "<fn>".into(),
+            }
+        }
+        MalValue::Closure(closure) => match closure.name {
+            Some(name) => format!("<closure {}: {}>", name, pr_arities(&closure.source)),
+            None => format!("<closure: {}>", pr_arities(&closure.source)),
+        },
+        MalValue::Compiled(closure) => match closure.proto.name {
+            Some(name) => format!("<closure {}: {}>", name, pr_arities(&closure.proto.source)),
+            None => format!("<closure: {}>", pr_arities(&closure.proto.source)),
+        },
+        MalValue::Code(closure) => match closure.name {
+            Some(name) => format!("<closure {}: {}>", name, pr_arities(&closure.source)),
+            None => format!("<closure: {}>", pr_arities(&closure.source)),
+        },
+        MalValue::Local { sym, .. } => sym.to_string(),
+        MalValue::Lambda(l) => match l.name {
+            Some(name) => format!("(fn* {} {})", name, pr_arities(&l.source)),
+            None => format!("(fn* {})", pr_arities(&l.source)),
+        },
+        MalValue::Let(l) => {
+            let val: Vec<_> = l
+                .bindings
+                .iter()
+                .map(|(k, v)| format!("{} {}", pr_str(k), pr_str(v)))
+                .collect();
+            format!("(let* [{}] {})", val.join(" "), pr_str(&l.body))
+        }
     }
 }
 
+fn pr_arities(arities: &[(MalValue, MalValue)]) -> String {
+    let val: Vec<_> = arities
+        .iter()
+        .map(|(params, body)| format!("({} {})", pr_str(params), pr_str(body)))
+        .collect();
+    val.join(" ")
+}
+
+/// Prints the strings as their contents and the regexes as their patterns, in collections too,
+/// like `str`
+pub fn print_str(val: &MalValue) -> String {
+    match val {
+        MalValue::String(s) => s.to_string(),
+        MalValue::Regex(re) => re.as_str().to_string(),
+        MalValue::List(list) => print_seq(list, '(', ')'),
+        MalValue::Vec(list) => print_seq(list, '[', ']'),
+        MalValue::Map(_) | MalValue::SortedMap(_) => {
+            let val: Vec<_> = val
+                .map_entries()
+                .into_iter()
+                .flatten()
+                .map(|(k, v)| format!("{} {}", print_str(&key_value(k)), print_str(v)))
+                .collect();
+            format!("{{{}}}", val.join(" "))
+        }
+        v => pr_str(v),
+    }
+}
+
+fn print_seq(vals: &[MalValue], start: char, end: char) -> String {
+    let vec: Vec<String> = vals.iter().map(print_str).collect();
+    format!("{}{}{}", start, vec.join(" "), end)
+}
+
 pub fn pr_seq(vals: Rc<Vec<MalValue>>, start: char, end: char) -> String {
-    let vec: Vec<String> = vals.iter().map(|v| pr_str(v)).collect();
+    let vec: Vec<String> = vals.iter().map(pr_str).collect();
     format!("{}{}{}", start, vec.join(" "), end)
 }
@@ -1,4 +1,4 @@
-use std::{collections::HashMap, rc::Rc};
+use std::rc::Rc;
 
 use crate::Result;
 use anyhow::{anyhow, Context};
@@ -6,11 +6,16 @@ use itertools::Itertools;
 use lazy_static::lazy_static;
 use regex::Regex;
 
-use crate::types::MalValue;
+use crate::{
+    symbol::Symbol,
+    types::{map_key, MalMap, MalValue},
+};
 
 pub struct Reader {
     tokens: Vec<String>,
     position: usize,
+    /// The source is not trusted, its new names are interned with [Symbol::try_new]
+    untrusted: bool,
 }
 
 impl Reader {
@@ -18,6 +23,7 @@ impl Reader {
         self.tokens.get(self.position)
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> Option<&String> {
         let position = self.position;
         self.position += 1;
@@ -30,14 +36,43 @@ pub fn read_str(str: &str) -> Result<MalValue> {
     let mut reader = Reader {
         tokens,
         position: 0,
+        untrusted: false,
     };
     read_form(&mut reader)
 }
 
+/// Reads all the forms of a source file
+pub fn read_all(str: &str) -> Result<Vec<MalValue>> {
+    read_forms(str, false)
+}
+
+/// Reads all the forms of a source that is not trusted, like sandboxed code or strings built by
+/// a program, failing once it would intern more than [crate::symbol::MAX_SYMBOLS] names
+pub fn read_untrusted(str: &str) -> Result<Vec<MalValue>> {
+    read_forms(str, true)
+}
+
+fn read_forms(str: &str, untrusted: bool) -> Result<Vec<MalValue>> {
+    let mut reader = Reader {
+        tokens: tokenize(str),
+        position: 0,
+        untrusted,
+    };
+    let mut forms = vec![];
+    while let Some(token) = reader.peek() {
+        if token.is_empty() {
+            reader.position += 1;
+        } else {
+            forms.push(read_form(&mut reader)?);
+        }
+    }
+    Ok(forms)
+}
+
 pub fn tokenize(str: &str) -> Vec<String> {
     lazy_static! {
         static ref REGEX: Regex = Regex::new(
-            r##"[\s,]*(~@|[\[\]{}()'`~^@]|"(?:\\.|[^\\"])*"?|;.*|[^\s\[\]{}('"`,;)]*)"##
+            r##"[\s,]*(~@|[\[\]{}()'`~^@]|#?"(?:\\.|[^\\"])*"?|;.*|[^\s\[\]{}('"`,;)]*)"##
         )
         .expect("Could not create regex");
     }
@@ -62,7 +97,7 @@ pub fn read_seq(reader: &mut Reader, end: SequenceDel) -> Result<MalValue> {
     loop {
         let token = reader.peek().context("List ended abruptly")?.clone();
         let val = read_form(reader)?;
-        if token.contains(end as u8 as char) {
+        if token.starts_with(end as u8 as char) {
             break;
         }
         vec.push(val);
@@ -75,23 +110,27 @@ pub fn read_seq(reader: &mut Reader, end: SequenceDel) -> Result<MalValue> {
 }
 
 pub fn hash_map(vec: Vec<MalValue>) -> Result<MalValue> {
-    if vec.len() % 2 != 0 {
-        return Err(anyhow!("Odd number of element in vector"));
+    if !vec.len().is_multiple_of(2) {
+        return Err(anyhow!(
+            "A map needs an even number of keys and values, got {}",
+            vec.len()
+        ));
     }
-    let map_res: Result<HashMap<String, MalValue>> = vec
+    let map_res: Result<MalMap> = vec
         .into_iter()
         .tuples()
-        .map(|(k, v)| match k {
-            MalValue::String(s) => Ok((format!("\"{}\"", s), v)),
-            MalValue::Atom(s) => Ok((format!(":{}", s), v)),
-            _ => Err(anyhow!("key is not string")),
-        })
+        .map(|(k, v)| Ok((map_key(&k)?, v)))
         .collect();
     let map = map_res?;
     Ok(MalValue::Map(Rc::new(map)))
 }
 
 pub fn read_atom(reader: &mut Reader) -> Result<MalValue> {
+    let untrusted = reader.untrusted;
+    let intern = |name: &str| match untrusted {
+        true => Symbol::try_new(name),
+        false => Ok(Symbol::new(name)),
+    };
     let token = reader.next();
     if let Some(t) = token {
         if t == "nil" {
@@ -102,19 +141,24 @@ pub fn read_atom(reader: &mut Reader) -> Result<MalValue> {
             Ok(MalValue::False)
         } else if let Ok(num) = t.parse::<i64>() {
             Ok(MalValue::Number(num))
-        } else if t.starts_with(':') {
-            let mut escaped = t.clone();
-            escaped.remove(0);
-            Ok(MalValue::Atom(escaped))
+        } else if let Some(keyword) = t.strip_prefix(':') {
+            Ok(MalValue::Atom(intern(keyword)?))
+        }
+        // The pattern is kept as written, the regex crate handles its escapes
+        else if t.len() > 2 && t.starts_with("#\"") && t.ends_with('"') {
+            let pattern = &t[2..t.len() - 1];
+            let re =
+                Regex::new(pattern).with_context(|| anyhow!("Invalid regex #\"{}\"", pattern))?;
+            Ok(MalValue::Regex(Rc::new(re)))
         }
         // Poor's man string parsing/escape. Should totally change that (will I though?)
         else if t.starts_with('"') && t.ends_with('"') {
             let mut escaped = t.clone();
             escaped.pop();
             escaped.remove(0);
-            Ok(MalValue::String(escaped))
+            Ok(MalValue::String(escaped.into()))
         } else {
-            Ok(MalValue::Sym(t.clone()))
+            Ok(MalValue::Sym(intern(t)?))
         }
     } else {
         Ok(MalValue::Nil)
@@ -0,0 +1,288 @@
+use std::rc::Rc;
+
+use crate::{
+    destructure::{body_with_defaults, pattern_syms, split_rest, with_defaults},
+    printer::pr_str,
+    symbol::{self, Symbol},
+    types::{Lambda, Let, MalValue},
+    Result,
+};
+use anyhow::anyhow;
+use itertools::Itertools;
+
+/// Names bound by a `fn*` or a `let*`, in the order of their slots
+#[derive(Default)]
+struct Scope {
+    names: Vec<Symbol>,
+    /// Number of names already bound when evaluated directly, the `let*` bindings are filled one
+    /// after the other
+    bound: usize,
+    is_fn: bool,
+    /// Locals of the enclosing scopes used by a `fn*`, with their `(depth, slot)` from outside
+    captures: Vec<(Symbol, usize, usize)>,
+    keeps_env: bool,
+}
+
+/// Resolves the local symbols of `fn*` and `let*` forms to their slots, so that they can be
+/// accessed without looking up the environments by name. Everything else stays a symbol resolved
+/// at runtime from the global environments.
+///
+/// A `fn*` only captures the locals its bodies use: they are copied into a frame between the
+/// frames of its calls and the global environment, instead of keeping every enclosing frame.
+#[derive(Default)]
+pub struct Resolver {
+    scopes: Vec<Scope>,
+}
+
+/// Resolves the arguments of a `fn*` form into a [MalValue::Lambda]
+pub fn resolve_fn(form: &[MalValue]) -> Result<MalValue> {
+    Resolver::default().resolve_lambda(form)
+}
+
+/// Resolves a `let*` form into a [MalValue::Let]
+pub fn resolve_let(bindings: &MalValue, body: &MalValue) -> Result<MalValue> {
+    Resolver::default().resolve_let(bindings, body)
+}
+
+/// `(params, body)` of each arity of a function
+pub type Arities = Vec<(MalValue, MalValue)>;
+
+/// Splits the arguments of a `fn*` form into its optional name and its `(params, body)` arities,
+/// either a single `[params] body` or one `([params] body)` list per arity
+pub fn fn_arities(form: &[MalValue]) -> Result<(Option<Symbol>, Arities)> {
+    let (name, form) = match form {
+        [MalValue::Sym(name), rest @ ..] => (Some(*name), rest),
+        _ => (None, form),
+    };
+    let is_arity = |a: &MalValue| matches!(a, MalValue::List(l) if matches!(l.first(), Some(MalValue::Vec(_))));
+    let arities = match form {
+        [params, body] if !(is_arity(params) && is_arity(body)) => {
+            vec![(params.clone(), body.clone())]
+        }
+        arities if !arities.is_empty() && arities.iter().all(is_arity) => arities
+            .iter()
+            .map(|a| match a {
+                MalValue::List(l) if l.len() == 2 => Ok((l[0].clone(), l[1].clone())),
+                v => Err(anyhow!(
+                    "Invalid arity, needs parameters and a body: {}",
+                    pr_str(v)
+                )),
+            })
+            .collect::<Result<_>>()?,
+        _ => return Err(anyhow!("Invalid number of arguments for fn")),
+    };
+    Ok((name, arities))
+}
+
+/// Expands `(defn name ...)` into `(def! name (fn* name ...))`, `defn-` into a `def-!`
+pub fn expand_defn(form: &[MalValue]) -> Result<MalValue> {
+    let def = match form.first() {
+        Some(MalValue::Sym(symbol::DEFN_PRIVATE)) => symbol::DEF_PRIVATE,
+        _ => symbol::DEF,
+    };
+    match form {
+        [_, name @ MalValue::Sym(_), rest @ ..] => {
+            let mut lambda = vec![MalValue::Sym(symbol::FN), name.clone()];
+            lambda.extend_from_slice(rest);
+            Ok(MalValue::List(Rc::new(vec![
+                MalValue::Sym(def),
+                name.clone(),
+                MalValue::List(Rc::new(lambda)),
+            ])))
+        }
+        _ => Err(anyhow!("defn needs a name")),
+    }
+}
+
+impl Resolver {
+    pub fn resolve(&mut self, ast: &MalValue) -> Result<MalValue> {
+        match ast {
+            MalValue::Sym(sym) => Ok(self.resolve_sym(*sym)),
+            MalValue::List(list) => match &list[..] {
+                [MalValue::Sym(symbol::FN), form @ ..] => self.resolve_lambda(form),
+                [MalValue::Sym(symbol::DEFN | symbol::DEFN_PRIVATE), ..] => {
+                    self.resolve(&expand_defn(list)?)
+                }
+                [MalValue::Sym(symbol::LET), bindings, body] => self.resolve_let(bindings, body),
+                [MalValue::Sym(symbol::LET), ..] => {
+                    Err(anyhow!("Invalid number of arguments to let"))
+                }
+                [def @ MalValue::Sym(symbol::DEF | symbol::DEF_PRIVATE), name, rest @ ..] => {
+                    let mut resolved = vec![def.clone(), name.clone()];
+                    for v in rest {
+                        resolved.push(self.resolve(v)?);
+                    }
+                    Ok(MalValue::List(Rc::new(resolved)))
+                }
+                [MalValue::Sym(symbol::NS | symbol::IN_NS), ..] => Ok(ast.clone()),
+                _ => Ok(MalValue::List(Rc::new(self.resolve_all(list)?))),
+            },
+            MalValue::Vec(vec) => Ok(MalValue::Vec(Rc::new(self.resolve_all(vec)?))),
+            MalValue::Map(map) => {
+                let map = map
+                    .iter()
+                    .map(|(k, v)| Ok((k.clone(), self.resolve(v)?)))
+                    .collect::<Result<_>>()?;
+                Ok(MalValue::Map(Rc::new(map)))
+            }
+            v => Ok(v.clone()),
+        }
+    }
+
+    fn resolve_all(&mut self, vals: &[MalValue]) -> Result<Vec<MalValue>> {
+        vals.iter().map(|v| self.resolve(v)).collect()
+    }
+
+    fn resolve_sym(&mut self, sym: Symbol) -> MalValue {
+        match self.resolve_local(sym, self.scopes.len(), false) {
+            Some((depth, slot)) => MalValue::Local { sym, depth, slot },
+            None => MalValue::Sym(sym),
+        }
+    }
+
+    /// `(depth, slot)` of a local from the innermost of the first `top` scopes, capturing it in
+    /// the `fn*` scopes crossed. With `deferred`, the `let*` bindings not bound yet are visible.
+    fn resolve_local(&mut self, sym: Symbol, top: usize, deferred: bool) -> Option<(usize, usize)> {
+        let mut depth = 0;
+        for i in (0..top).rev() {
+            let scope = &self.scopes[i];
+            let bound = if deferred {
+                scope.names.len()
+            } else {
+                scope.bound
+            };
+            if let Some(slot) = scope.names[..bound].iter().rposition(|n| *n == sym) {
+                return Some((depth, slot));
+            }
+            depth += 1;
+            if !scope.is_fn {
+                continue;
+            }
+            // Past the frames of the calls, the frame of the captured locals
+            if let Some(slot) = scope.captures.iter().position(|c| c.0 == sym) {
+                return Some((depth, slot));
+            }
+            if let Some((d, s)) = self.resolve_local(sym, i, false) {
+                let captures = &mut self.scopes[i].captures;
+                captures.push((sym, d, s));
+                return Some((depth, captures.len() - 1));
+            }
+            // Code inside a `fn*` runs later, once all the bindings of the enclosing `let*` are set
+            let (d, s) = self.resolve_local(sym, i, true)?;
+            self.scopes[i].keeps_env = true;
+            return Some((depth + 1 + d, s));
+        }
+        None
+    }
+
+    fn resolve_lambda(&mut self, form: &[MalValue]) -> Result<MalValue> {
+        let (name, arities) = fn_arities(form)?;
+
+        let mut fixed = vec![];
+        let mut variadic = false;
+        let mut resolved = vec![];
+        // The arities share the captured frame
+        let (mut captures, mut keeps_env) = (vec![], false);
+        for (params, body) in &arities {
+            let mut names = Vec::from_iter(name);
+            match params {
+                MalValue::List(p) | MalValue::Vec(p) => {
+                    let (f, rest) = split_rest(p)?;
+                    if rest.is_some() {
+                        if variadic {
+                            return Err(anyhow!("Cannot have more than one variadic arity"));
+                        }
+                        variadic = true;
+                    } else if fixed.contains(&f.len()) {
+                        return Err(anyhow!(
+                            "Cannot have two arities with {} parameters",
+                            f.len()
+                        ));
+                    } else {
+                        fixed.push(f.len());
+                    }
+                    pattern_syms(params, &mut names)?
+                }
+                v => {
+                    return Err(anyhow!(
+                        "Invalid parameters, needs to be a vector: {}",
+                        pr_str(v)
+                    ))
+                }
+            };
+            self.scopes.push(Scope {
+                bound: names.len(),
+                names,
+                is_fn: true,
+                captures,
+                keeps_env,
+            });
+            let body = body_with_defaults(params, body).and_then(|b| self.resolve(&b));
+            let scope = self.scopes.pop().unwrap_or_default();
+            (captures, keeps_env) = (scope.captures, scope.keeps_env);
+            resolved.push((params.clone(), body?));
+        }
+
+        Ok(MalValue::Lambda(Rc::new(Lambda {
+            name,
+            arities: Rc::new(resolved),
+            source: Rc::new(arities),
+            captures: captures.into_iter().map(|(_, d, s)| (d, s)).collect(),
+            keeps_env,
+        })))
+    }
+
+    fn resolve_let(&mut self, bindings: &MalValue, body: &MalValue) -> Result<MalValue> {
+        let bindings = match bindings {
+            MalValue::List(b) | MalValue::Vec(b) if b.len().is_multiple_of(2) => b,
+            MalValue::List(_) | MalValue::Vec(_) => {
+                return Err(anyhow!(
+                    "Invalid let  bindings, needs to have a key, value pair"
+                ))
+            }
+            v => {
+                return Err(anyhow!(
+                    "Let bindings needs a list, obtained: {}",
+                    pr_str(v)
+                ))
+            }
+        };
+        let bindings = with_defaults(bindings)?;
+        let mut names = vec![];
+        for k in bindings.iter().step_by(2) {
+            pattern_syms(k, &mut names)?;
+        }
+
+        self.scopes.push(Scope {
+            names,
+            ..Scope::default()
+        });
+        let res = self.resolve_let_body(&bindings, body);
+        let slots = self.scopes.pop().map_or(0, |s| s.names.len());
+        let (bindings, body) = res?;
+
+        Ok(MalValue::Let(Rc::new(Let {
+            bindings,
+            slots,
+            body,
+        })))
+    }
+
+    fn resolve_let_body(
+        &mut self,
+        bindings: &[MalValue],
+        body: &MalValue,
+    ) -> Result<(Vec<(MalValue, MalValue)>, MalValue)> {
+        let mut resolved = vec![];
+        for (k, v) in bindings.iter().tuples() {
+            let v = self.resolve(v)?;
+            let mut names = vec![];
+            pattern_syms(k, &mut names)?;
+            if let Some(scope) = self.scopes.last_mut() {
+                scope.bound += names.len();
+            }
+            resolved.push((k.clone(), v));
+        }
+        Ok((resolved, self.resolve(body)?))
+    }
+}
@@ -0,0 +1,187 @@
+//! Environments for untrusted code: only the builtins of an allowlist, run within a [Budget].
+//!
+//! The builtins needing a [Capability] that were not allowed are bound to functions failing with
+//! a [CapabilityError], and while sandboxed code runs the namespaces of the process can't be
+//! reached, neither by `ns` and `in-ns` nor by qualified symbols.
+
+use std::{cell::Cell, fmt, rc::Rc, time::Duration};
+
+use crate::{
+    base_fn::{self, Builtin},
+    budget::{self, Budget},
+    env::{env_set_sym, Env},
+    reader::read_untrusted,
+    symbol::Symbol,
+    types::MalValue,
+    vm, Result,
+};
+use anyhow::anyhow;
+
+/// Name of the namespace sandboxed code is evaluated in
+pub const SANDBOX_NS: &str = "sandbox";
+
+/// Access to the outside of the interpreter a builtin or a form needs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    /// Printing, reading and loading files
+    Io,
+    /// Switching to, requiring or resolving symbols of the namespaces of the process
+    Namespaces,
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Capability::Io => write!(f, "I/O"),
+            Capability::Namespaces => write!(f, "namespaces"),
+        }
+    }
+}
+
+/// Error of sandboxed code using a [Capability] it wasn't given, find it with
+/// [anyhow::Error::downcast_ref]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CapabilityError(pub Capability);
+
+impl fmt::Display for CapabilityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Capability error: the sandbox does not allow {}", self.0)
+    }
+}
+
+impl std::error::Error for CapabilityError {}
+
+thread_local! {
+    static ACTIVE: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Fails with a [CapabilityError] while sandboxed code runs
+pub fn check(capability: Capability) -> Result<()> {
+    match is_active() {
+        true => Err(CapabilityError(capability).into()),
+        false => Ok(()),
+    }
+}
+
+/// Whether sandboxed code is running on this thread
+pub fn is_active() -> bool {
+    ACTIVE.with(Cell::get)
+}
+
+fn deny_io(_: &[MalValue]) -> Result<MalValue> {
+    Err(CapabilityError(Capability::Io).into())
+}
+
+/// Builds a [Sandbox], nothing is allowed by default
+#[derive(Debug, Default, Clone)]
+pub struct SandboxBuilder {
+    allowed: Vec<String>,
+    pure: bool,
+    budget: Budget,
+}
+
+impl SandboxBuilder {
+    /// Allows the builtin `name`, granting the capability it needs
+    pub fn allow(mut self, name: &str) -> Self {
+        self.allowed.push(name.to_string());
+        self
+    }
+
+    /// Allows every builtin needing no capability
+    pub fn allow_pure(mut self) -> Self {
+        self.pure = true;
+        self
+    }
+
+    /// Limits of each evaluation, replacing the ones set before
+    pub fn budget(mut self, budget: Budget) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// Maximum number of function calls of each evaluation
+    pub fn fuel(mut self, fuel: u64) -> Self {
+        self.budget.fuel = Some(fuel);
+        self
+    }
+
+    /// Maximum duration of each evaluation
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.budget.timeout = Some(timeout);
+        self
+    }
+
+    /// Maximum length of the collections and strings returned by the builtins
+    pub fn max_len(mut self, len: usize) -> Self {
+        self.budget.max_len = Some(len);
+        self
+    }
+
+    /// Maximum number of bytes allocated by each evaluation, see [budget::CountingAlloc]
+    pub fn memory(mut self, bytes: usize) -> Self {
+        self.budget.memory = Some(bytes);
+        self
+    }
+
+    pub fn build(self) -> Result<Sandbox> {
+        if let Some(name) = self
+            .allowed
+            .iter()
+            .find(|a| !base_fn::BUILTINS.iter().any(|b| b.name == *a))
+        {
+            return Err(anyhow!(
+                "Unknown builtin in the sandbox allowlist: {}",
+                name
+            ));
+        }
+        if self.budget.memory.is_some() && budget::allocated().is_none() {
+            return Err(anyhow!(
+                "Sandbox memory limits need budget::CountingAlloc as global allocator"
+            ));
+        }
+        let allowed = |b: &Builtin| {
+            self.allowed.iter().any(|a| a == b.name) || (self.pure && b.needs.is_none())
+        };
+
+        let core = Rc::new(Env::new());
+        for builtin in base_fn::BUILTINS {
+            let func = match builtin.needs {
+                _ if allowed(builtin) => builtin.func,
+                Some(Capability::Io) => deny_io,
+                _ => continue,
+            };
+            env_set_sym(&core, builtin.name.into(), MalValue::Function(func));
+        }
+        Ok(Sandbox {
+            env: Rc::new(Env::new_ns(core, Symbol::new(SANDBOX_NS))),
+            budget: self.budget,
+        })
+    }
+}
+
+/// Environment of untrusted code, separate from the namespaces of the process
+#[derive(Debug)]
+pub struct Sandbox {
+    pub env: Rc<Env>,
+    pub budget: Budget,
+}
+
+impl Sandbox {
+    pub fn builder() -> SandboxBuilder {
+        SandboxBuilder::default()
+    }
+
+    /// Evaluates the forms of the source within the budget, returns the value of the last one.
+    /// Definitions are kept for the following evaluations.
+    pub fn eval(&self, source: &str) -> Result<MalValue> {
+        let forms = read_untrusted(source)?;
+        let was_active = ACTIVE.with(|a| a.replace(true));
+        let res = budget::run(self.budget, || {
+            forms
+                .iter()
+                .try_fold(MalValue::Nil, |_, ast| vm::eval(&self.env, ast))
+        });
+        ACTIVE.with(|a| a.set(was_active));
+        res
+    }
+}
@@ -0,0 +1,159 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt,
+    hash::{BuildHasherDefault, Hasher},
+    marker::PhantomData,
+};
+
+use crate::Result;
+use anyhow::anyhow;
+
+/// Interned symbol or keyword name, compared and hashed by its id in the table of the thread.
+/// Not `Send`, the id meaning nothing in the table of another thread:
+///
+/// ```compile_fail
+/// fn send<T: Send>() {}
+/// send::<mal_rust::symbol::Symbol>();
+/// ```
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Symbol(u32, PhantomData<*const ()>);
+
+/// Symbols interned before anything else, so they can be matched as constants
+const WELL_KNOWN: [&str; 15] = [
+    "def!", "def-!", "let*", "do", "if", "fn*", "ns", "in-ns", "require", "as", "refer", "all",
+    "&", "defn", "defn-",
+];
+pub const DEF: Symbol = Symbol(0, PhantomData);
+pub const DEF_PRIVATE: Symbol = Symbol(1, PhantomData);
+pub const LET: Symbol = Symbol(2, PhantomData);
+pub const DO: Symbol = Symbol(3, PhantomData);
+pub const IF: Symbol = Symbol(4, PhantomData);
+pub const FN: Symbol = Symbol(5, PhantomData);
+pub const NS: Symbol = Symbol(6, PhantomData);
+pub const IN_NS: Symbol = Symbol(7, PhantomData);
+pub const REQUIRE: Symbol = Symbol(8, PhantomData);
+pub const AS: Symbol = Symbol(9, PhantomData);
+pub const REFER: Symbol = Symbol(10, PhantomData);
+pub const ALL: Symbol = Symbol(11, PhantomData);
+pub const AMPERSAND: Symbol = Symbol(12, PhantomData);
+pub const DEFN: Symbol = Symbol(13, PhantomData);
+pub const DEFN_PRIVATE: Symbol = Symbol(14, PhantomData);
+
+/// Most names in the table for [Symbol::try_new] to intern a new one, the names made by a
+/// program while it runs being never freed
+pub const MAX_SYMBOLS: usize = 1 << 16;
+
+struct Interner {
+    ids: HashMap<&'static str, Symbol>,
+    names: Vec<&'static str>,
+    qualified: Vec<Option<(Symbol, Symbol)>>,
+}
+
+impl Interner {
+    fn new() -> Self {
+        let mut interner = Self {
+            ids: HashMap::new(),
+            names: vec![],
+            qualified: vec![],
+        };
+        for name in WELL_KNOWN {
+            interner.intern(name);
+        }
+        interner
+    }
+
+    fn intern(&mut self, name: &str) -> Symbol {
+        if let Some(sym) = self.ids.get(name) {
+            return *sym;
+        }
+        // Interned names live as long as the program, like the symbols pointing to them
+        let name: &'static str = Box::leak(name.to_string().into_boxed_str());
+        let qualified = match name.split_once('/') {
+            Some((ns, n)) if !ns.is_empty() && !n.is_empty() => {
+                Some((self.intern(ns), self.intern(n)))
+            }
+            _ => None,
+        };
+        let sym = Symbol(self.names.len() as u32, PhantomData);
+        self.ids.insert(name, sym);
+        self.names.push(name);
+        self.qualified.push(qualified);
+        sym
+    }
+}
+
+thread_local! {
+    static INTERNER: RefCell<Interner> = RefCell::new(Interner::new());
+}
+
+impl Symbol {
+    /// Interns `name`, returning the same [Self] for the same name
+    pub fn new(name: &str) -> Self {
+        INTERNER.with(|i| i.borrow_mut().intern(name))
+    }
+
+    /// Interns `name` like [Self::new], failing if it is new and the table is full. For the
+    /// names coming from the values of a program rather than from its source.
+    pub fn try_new(name: &str) -> Result<Self> {
+        INTERNER.with(|i| {
+            let mut i = i.borrow_mut();
+            match i.ids.get(name) {
+                Some(sym) => Ok(*sym),
+                None if i.names.len() >= MAX_SYMBOLS => {
+                    Err(anyhow!("Too many symbols, {} names interned", MAX_SYMBOLS))
+                }
+                None => Ok(i.intern(name)),
+            }
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        INTERNER.with(|i| i.borrow().names[self.0 as usize])
+    }
+
+    /// Namespace and name of a `ns/name` symbol, [None] if the symbol is not qualified
+    pub fn qualified(&self) -> Option<(Symbol, Symbol)> {
+        INTERNER.with(|i| i.borrow().qualified[self.0 as usize])
+    }
+}
+
+impl From<&str> for Symbol {
+    fn from(value: &str) -> Self {
+        Symbol::new(value)
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Symbol({}, {:?})", self.0, self.name())
+    }
+}
+
+/// Hashes a [Symbol] by its id with a single multiplication, ids are already unique
+#[derive(Default)]
+pub struct SymbolHasher(u64);
+
+impl Hasher for SymbolHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 = (self.0 << 8) | *b as u64;
+        }
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.0 = (i as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    }
+}
+
+pub type SymbolMap<V> = HashMap<Symbol, V, BuildHasherDefault<SymbolHasher>>;
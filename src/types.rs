@@ -1,45 +1,383 @@
 use crate::{
+    budget, closure_compiler,
+    destructure::split_rest,
     env::{env_bind, Env},
     printer::{pr_seq, pr_str},
-    Result,
+    symbol::Symbol,
+    vm, Result,
 };
 use anyhow::anyhow;
-use std::{collections::HashMap, rc::Rc};
+use indexmap::IndexMap;
+use itertools::Itertools;
+use regex::Regex;
+use std::{
+    cmp::Ordering,
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    rc::Rc,
+};
+
+/// Entries of a map by printed key, see [map_key], iterated in the order they were inserted
+pub type MalMap = IndexMap<String, MalValue>;
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+/// Values of mal, see the [PartialEq] implementation for their equality
+#[derive(Debug, Clone)]
 pub enum MalValue {
     Nil,
     True,
     False,
     Number(i64),
-    Sym(String),
-    Atom(String),
-    String(String),
+    Sym(Symbol),
+    Atom(Symbol),
+    String(Rc<str>),
     List(Rc<Vec<MalValue>>),
     Vec(Rc<Vec<MalValue>>),
-    Map(Rc<HashMap<String, MalValue>>),
-    Function(fn(&Vec<MalValue>) -> Result<MalValue>),
-    Closure {
-        func: fn(&mut Rc<Env>, &MalValue) -> Result<MalValue>,
-        env: Rc<Env>,
-        ast: Rc<MalValue>,
-        params: Rc<MalValue>,
+    Map(Rc<MalMap>),
+    /// Map iterated in the order of its keys, from `sorted-map` and `sorted-map-by`
+    SortedMap(Rc<SortedMap>),
+    /// Compiled regular expression, from a `#"..."` literal or `re-pattern`
+    Regex(Rc<Regex>),
+    Function(fn(&[MalValue]) -> Result<MalValue>),
+    /// Function defined in mal, boxed to keep the values small
+    Closure(Rc<Closure>),
+    /// Local binding resolved to the `slot` of the environment `depth` levels up
+    Local {
+        sym: Symbol,
+        depth: usize,
+        slot: usize,
     },
+    /// `fn*` form whose bodies have been resolved
+    Lambda(Rc<Lambda>),
+    /// `let*` form whose bindings and body have been resolved
+    Let(Rc<Let>),
+    /// Function compiled to bytecode, run by the [crate::vm]
+    Compiled(Rc<vm::Closure>),
+    /// Function whose bodies were compiled to Rust closures by the [crate::closure_compiler]
+    Code(Rc<closure_compiler::Closure>),
+}
+
+// Values are cloned all the time, the large payloads are boxed to keep them small
+const _: () = assert!(std::mem::size_of::<MalValue>() <= 24);
+
+/// Function defined in mal, with one `(params, body)` per arity
+#[derive(Debug)]
+pub struct Closure {
+    pub func: fn(&mut Rc<Env>, &MalValue) -> Result<MalValue>,
+    pub env: Rc<Env>,
+    pub name: Option<Symbol>,
+    pub arities: Rc<Vec<(MalValue, MalValue)>>,
+    /// The arities as written, printed instead of the resolved ones like by the other engines
+    pub source: Rc<Vec<(MalValue, MalValue)>>,
+}
+
+/// Resolved `fn*`, the name is bound to the function itself
+#[derive(Debug, PartialEq, Eq)]
+pub struct Lambda {
+    pub name: Option<Symbol>,
+    pub arities: Rc<Vec<(MalValue, MalValue)>>,
+    /// The arities as written, before their resolution
+    pub source: Rc<Vec<(MalValue, MalValue)>>,
+    /// `(depth, slot)` of the locals used by the bodies, from the environment the closure is
+    /// created in. Their values fill the slots of the frame the closure keeps as environment.
+    pub captures: Vec<(usize, usize)>,
+    /// The bodies use locals of a `let*` not bound yet when the closure is created, looked up
+    /// through the environment it was created in, kept as parent of the captured frame
+    pub keeps_env: bool,
+}
+
+/// Equality of mal: lists and vectors with equal items are equal, maps and sorted maps with the
+/// same entries whatever their order, regular expressions with the same pattern, and functions
+/// only to themselves
+impl PartialEq for MalValue {
+    fn eq(&self, other: &Self) -> bool {
+        use MalValue::*;
+        match (self, other) {
+            (Nil, Nil) | (True, True) | (False, False) => true,
+            (Number(a), Number(b)) => a == b,
+            (Sym(a), Sym(b)) | (Atom(a), Atom(b)) => a == b,
+            (String(a), String(b)) => a == b,
+            (List(a) | Vec(a), List(b) | Vec(b)) => a == b,
+            (Map(_) | SortedMap(_), Map(_) | SortedMap(_)) => {
+                self.map_entries() == other.map_entries()
+            }
+            (Regex(a), Regex(b)) => a.as_str() == b.as_str(),
+            (Function(a), Function(b)) => std::ptr::fn_addr_eq(*a, *b),
+            (Closure(a), Closure(b)) => Rc::ptr_eq(a, b),
+            (Compiled(a), Compiled(b)) => Rc::ptr_eq(a, b),
+            (Code(a), Code(b)) => Rc::ptr_eq(a, b),
+            (
+                Local { sym, depth, slot },
+                Local {
+                    sym: s,
+                    depth: d,
+                    slot: sl,
+                },
+            ) => (sym, depth, slot) == (s, d, sl),
+            (Lambda(a), Lambda(b)) => a == b,
+            (Let(a), Let(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for MalValue {}
+
+/// Consistent with the equality: lists and vectors hash alike, and the entries of maps are
+/// combined whatever their order
+impl Hash for MalValue {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        use MalValue::*;
+        match self {
+            List(l) | Vec(l) => {
+                state.write_u8(b'(');
+                l.hash(state)
+            }
+            Map(_) | SortedMap(_) => {
+                state.write_u8(b'{');
+                let entries = self.map_entries().into_iter().flatten();
+                let sum = entries.fold(0u64, |sum, entry| {
+                    let mut hasher = DefaultHasher::new();
+                    entry.hash(&mut hasher);
+                    sum.wrapping_add(hasher.finish())
+                });
+                state.write_u64(sum)
+            }
+            Regex(r) => {
+                state.write_u8(b'#');
+                r.as_str().hash(state)
+            }
+            Function(f) => (*f as usize).hash(state),
+            Closure(c) => Rc::as_ptr(c).hash(state),
+            Compiled(c) => Rc::as_ptr(c).hash(state),
+            Code(c) => Rc::as_ptr(c).hash(state),
+            v => {
+                std::mem::discriminant(v).hash(state);
+                match v {
+                    Number(n) => n.hash(state),
+                    Sym(s) | Atom(s) | Local { sym: s, .. } => s.hash(state),
+                    String(s) => s.hash(state),
+                    _ => (),
+                }
+            }
+        }
+    }
+}
+
+/// Total order of mal values: nil, false, true, the numbers, strings, keywords and symbols in
+/// their natural order, then the lists and vectors lexicographically, the maps by size and
+/// entries and the regular expressions by pattern. Functions are ordered by address, only to be
+/// consistent with their identity: the order changes from a run to the next, and `compare` and
+/// `sort` reject them, see [check_comparable].
+impl Ord for MalValue {
+    fn cmp(&self, other: &Self) -> Ordering {
+        use MalValue::*;
+        match (self, other) {
+            (Number(a), Number(b)) => a.cmp(b),
+            (String(a), String(b)) => a.cmp(b),
+            (Sym(a), Sym(b)) | (Atom(a), Atom(b)) => a.name().cmp(b.name()),
+            (List(a) | Vec(a), List(b) | Vec(b)) => a.iter().cmp(b.iter()),
+            (Map(_) | SortedMap(_), Map(_) | SortedMap(_)) => {
+                let (a, b) = (sorted_entries(self), sorted_entries(other));
+                a.len().cmp(&b.len()).then_with(|| a.cmp(&b))
+            }
+            (Regex(a), Regex(b)) => a.as_str().cmp(b.as_str()),
+            (Function(a), Function(b)) => (*a as usize).cmp(&(*b as usize)),
+            (Closure(a), Closure(b)) => Rc::as_ptr(a).cmp(&Rc::as_ptr(b)),
+            (Compiled(a), Compiled(b)) => Rc::as_ptr(a).cmp(&Rc::as_ptr(b)),
+            (Code(a), Code(b)) => Rc::as_ptr(a).cmp(&Rc::as_ptr(b)),
+            (
+                Local { sym, depth, slot },
+                Local {
+                    sym: s,
+                    depth: d,
+                    slot: sl,
+                },
+            ) => (sym.name(), depth, slot).cmp(&(s.name(), d, sl)),
+            (Lambda(_), Lambda(_)) | (Let(_), Let(_)) => pr_str(self).cmp(&pr_str(other)),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+}
+
+/// Entries of a map by printed key, see [MalValue::map_entries]
+fn sorted_entries(map: &MalValue) -> Vec<(&String, &MalValue)> {
+    let mut entries: Vec<_> = map.map_entries().into_iter().flatten().collect();
+    entries.sort_unstable_by_key(|(k, _)| *k);
+    entries
+}
+
+impl PartialOrd for MalValue {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Fails if the value is or holds a function, whose order is only that of its address
+pub fn check_comparable(val: &MalValue) -> Result<()> {
+    match val {
+        MalValue::List(l) | MalValue::Vec(l) => l.iter().try_for_each(check_comparable),
+        MalValue::Map(_) | MalValue::SortedMap(_) => val
+            .map_entries()
+            .into_iter()
+            .flatten()
+            .try_for_each(|(_, v)| check_comparable(v)),
+        MalValue::Function(_)
+        | MalValue::Closure(_)
+        | MalValue::Compiled(_)
+        | MalValue::Code(_) => Err(anyhow!("Functions cannot be compared: {}", pr_str(val))),
+        _ => Ok(()),
+    }
+}
+
+/// Orders two values with a comparator returning a number like `compare`, or a boolean telling
+/// whether the first one comes before the second like `<`
+pub fn compare_with(by: &MalValue, a: &MalValue, b: &MalValue) -> Result<Ordering> {
+    match by.apply(Rc::new(vec![a.clone(), b.clone()]))? {
+        MalValue::Number(n) => Ok(n.cmp(&0)),
+        MalValue::True => Ok(Ordering::Less),
+        MalValue::False | MalValue::Nil => match by.apply(Rc::new(vec![b.clone(), a.clone()]))? {
+            MalValue::True => Ok(Ordering::Greater),
+            _ => Ok(Ordering::Equal),
+        },
+        v => Err(anyhow!("The comparator returned: {}", pr_str(&v))),
+    }
+}
+
+/// Stable merge sort with a fallible comparator, stopping at its first error. Unlike
+/// [slice::sort_by] it does not panic when the comparator is not a total order, the order of the
+/// items is then unspecified.
+pub fn merge_sort_by<T>(
+    mut items: Vec<T>,
+    cmp: &mut impl FnMut(&T, &T) -> Result<Ordering>,
+) -> Result<Vec<T>> {
+    if items.len() <= 1 {
+        return Ok(items);
+    }
+    let right = items.split_off(items.len() / 2);
+    let mut left = merge_sort_by(items, cmp)?.into_iter().peekable();
+    let mut right = merge_sort_by(right, cmp)?.into_iter().peekable();
+    let mut sorted = Vec::with_capacity(left.len() + right.len());
+    while let (Some(l), Some(r)) = (left.peek(), right.peek()) {
+        let next = match cmp(r, l)? {
+            Ordering::Less => right.next(),
+            _ => left.next(),
+        };
+        sorted.extend(next);
+    }
+    sorted.extend(left);
+    sorted.extend(right);
+    Ok(sorted)
+}
+
+/// Entries kept sorted by key, with the order of the values or the comparator of
+/// `sorted-map-by`
+#[derive(Debug)]
+pub struct SortedMap {
+    pub entries: MalMap,
+    pub by: Option<MalValue>,
+}
+
+impl SortedMap {
+    /// Sorts the entries, the last value of a key repeated wins
+    pub fn new(
+        entries: impl IntoIterator<Item = (String, MalValue)>,
+        by: Option<MalValue>,
+    ) -> Result<Self> {
+        let entries: MalMap = entries.into_iter().collect();
+        let entries = merge_sort_by(entries.into_iter().collect(), &mut |(k1, _), (k2, _)| {
+            let (k1, k2) = (key_value(k1), key_value(k2));
+            match &by {
+                None => Ok(k1.cmp(&k2)),
+                Some(by) => compare_with(by, &k1, &k2),
+            }
+        })?;
+        Ok(SortedMap {
+            entries: entries.into_iter().collect(),
+            by,
+        })
+    }
+}
+
+/// Printed key of a map entry, maps are keyed by strings, keywords and symbols
+pub fn map_key(key: &MalValue) -> Result<String> {
+    match key {
+        MalValue::String(s) => Ok(format!("\"{}\"", s)),
+        MalValue::Atom(s) => Ok(format!(":{}", s)),
+        MalValue::Sym(s) => Ok(s.to_string()),
+        k => Err(anyhow!(
+            "Map keys are strings, keywords or symbols, got: {}",
+            pr_str(k)
+        )),
+    }
+}
+
+/// Key of a map entry from its printed form, see [map_key]
+pub fn key_value(key: &str) -> MalValue {
+    match key.strip_prefix(':') {
+        Some(kw) => MalValue::Atom(kw.into()),
+        None if key.starts_with('"') => MalValue::String(key[1..key.len() - 1].into()),
+        None => MalValue::Sym(key.into()),
+    }
+}
+
+/// Resolved `let*`, each binding pattern filling the next slots
+#[derive(Debug, PartialEq, Eq)]
+pub struct Let {
+    pub bindings: Vec<(MalValue, MalValue)>,
+    pub slots: usize,
+    pub body: MalValue,
 }
 
 impl MalValue {
+    /// Position of the type in the order of the values, see the [Ord] implementation
+    fn rank(&self) -> u8 {
+        match self {
+            MalValue::Nil => 0,
+            MalValue::False => 1,
+            MalValue::True => 2,
+            MalValue::Number(_) => 3,
+            MalValue::String(_) => 4,
+            MalValue::Atom(_) => 5,
+            MalValue::Sym(_) => 6,
+            MalValue::List(_) | MalValue::Vec(_) => 7,
+            MalValue::Map(_) | MalValue::SortedMap(_) => 8,
+            MalValue::Regex(_) => 9,
+            MalValue::Function(_) => 10,
+            MalValue::Closure(_) => 11,
+            MalValue::Compiled(_) => 12,
+            MalValue::Code(_) => 13,
+            MalValue::Local { .. } => 14,
+            MalValue::Lambda(_) => 15,
+            MalValue::Let(_) => 16,
+        }
+    }
+
+    /// Entries of a map or a sorted map, in the order they are iterated
+    pub fn map_entries(&self) -> Option<&MalMap> {
+        match self {
+            MalValue::Map(m) => Some(m),
+            MalValue::SortedMap(m) => Some(&m.entries),
+            _ => None,
+        }
+    }
+
     pub fn apply(&self, args: Rc<Vec<MalValue>>) -> Result<MalValue> {
         match self {
-            MalValue::Function(f) => f(&args),
-            MalValue::Closure {
-                func,
-                env,
-                ast,
-                params,
-            } => {
-                let mut env = env_bind(&env, &params, args)?;
-                func(&mut env, ast)
+            MalValue::Function(f) => {
+                let val = f(&args)?;
+                budget::check_len(&val)?;
+                Ok(val)
+            }
+            MalValue::Closure(c) => {
+                let (params, body) = select_arity(&c.arities, args.len()).ok_or_else(|| {
+                    arity_error(args.len(), c.name, c.arities.iter().map(|(p, _)| p))
+                })?;
+                let this = c.name.map(|_| self.clone());
+                let mut env = env_bind(&c.env, params, args, this)?;
+                (c.func)(&mut env, body)
             }
+            MalValue::Compiled(closure) => vm::call(closure, args),
+            MalValue::Code(closure) => closure_compiler::call(closure, args),
             v => Err(anyhow!(
                 "Cannot evaluate anything other than a function: {}, {}",
                 pr_str(v),
@@ -49,6 +387,38 @@ impl MalValue {
     }
 }
 
+/// Error for a call that no arity of the function accepts
+pub fn arity_error<'a>(
+    argc: usize,
+    name: Option<Symbol>,
+    params: impl Iterator<Item = &'a MalValue>,
+) -> anyhow::Error {
+    anyhow!(
+        "Wrong number of args ({}) passed to {}, available arities: {}",
+        argc,
+        name.map_or("anonymous fn".to_string(), |n| n.to_string()),
+        params.map(pr_str).join(", ")
+    )
+}
+
+/// Selects the arity with exactly `argc` parameters, or else the variadic one accepting them
+pub fn select_arity(
+    arities: &[(MalValue, MalValue)],
+    argc: usize,
+) -> Option<&(MalValue, MalValue)> {
+    let mut variadic = None;
+    for arity in arities {
+        if let MalValue::List(p) | MalValue::Vec(p) = &arity.0 {
+            match split_rest(p) {
+                Ok((fixed, None)) if fixed.len() == argc => return Some(arity),
+                Ok((fixed, Some(_))) if fixed.len() <= argc => variadic = Some(arity),
+                _ => (),
+            }
+        }
+    }
+    variadic
+}
+
 impl From<bool> for MalValue {
     fn from(value: bool) -> Self {
         match value {
@@ -0,0 +1,309 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{
+    budget,
+    bytecode::{Arity, Capture, Op, Proto},
+    compiler::compile,
+    destructure::destructure,
+    env::{bind_params, env_set_sym, Env},
+    gc, namespace,
+    printer::pr_str,
+    sandbox::{self, Capability},
+    types::{arity_error, MalMap, MalValue},
+    Result,
+};
+use anyhow::{anyhow, Context};
+
+/// Function compiled to bytecode, with the values it captured from the enclosing functions
+#[derive(Debug)]
+pub struct Closure {
+    pub proto: Rc<Proto>,
+    pub upvalues: Vec<Rc<RefCell<MalValue>>>,
+    pub env: Rc<Env>,
+}
+
+/// Local of a frame, boxed in a cell once captured by a closure so they share it
+enum Slot {
+    Val(MalValue),
+    Cell(Rc<RefCell<MalValue>>),
+}
+
+impl Slot {
+    fn get(&self) -> MalValue {
+        match self {
+            Slot::Val(v) => v.clone(),
+            Slot::Cell(c) => c.borrow().clone(),
+        }
+    }
+
+    fn set(&mut self, val: MalValue) {
+        match self {
+            Slot::Val(v) => *v = val,
+            Slot::Cell(c) => *c.borrow_mut() = val,
+        }
+    }
+
+    fn capture(&mut self) -> Rc<RefCell<MalValue>> {
+        if let Slot::Val(v) = self {
+            let v = std::mem::replace(v, MalValue::Nil);
+            let cell = Rc::new(RefCell::new(v));
+            gc::track_upvalue(&cell);
+            *self = Slot::Cell(cell);
+        }
+        match self {
+            Slot::Cell(c) => c.clone(),
+            Slot::Val(_) => unreachable!("Slot was just boxed"),
+        }
+    }
+}
+
+struct Frame {
+    closure: Rc<Closure>,
+    arity: usize,
+    ip: usize,
+    locals: Vec<Slot>,
+    env: Rc<Env>,
+    /// Stack length when the frame was entered
+    base: usize,
+    _depth: budget::Depth,
+}
+
+impl Frame {
+    fn arity(&self) -> &Arity {
+        &self.closure.proto.arities[self.arity]
+    }
+}
+
+#[derive(Default)]
+struct Vm {
+    stack: Vec<MalValue>,
+    frames: Vec<Frame>,
+}
+
+/// Compiles the form and runs it in `env`
+pub fn eval(env: &Rc<Env>, ast: &MalValue) -> Result<MalValue> {
+    run(compile(ast)?, env)
+}
+
+/// Runs a compiled top level form in `env`
+pub fn run(proto: Rc<Proto>, env: &Rc<Env>) -> Result<MalValue> {
+    let closure = Rc::new(Closure {
+        proto,
+        upvalues: vec![],
+        env: env.clone(),
+    });
+    call(&closure, Rc::new(vec![]))
+}
+
+/// Calls a compiled closure with the arguments
+pub fn call(closure: &Rc<Closure>, args: Rc<Vec<MalValue>>) -> Result<MalValue> {
+    let mut vm = Vm::default();
+    vm.push_frame(closure.clone(), args)?;
+    vm.run()
+}
+
+impl Vm {
+    fn push_frame(&mut self, closure: Rc<Closure>, args: Rc<Vec<MalValue>>) -> Result<()> {
+        let depth = budget::enter()?;
+        let proto = &closure.proto;
+        let arity = proto
+            .arities
+            .iter()
+            .position(|a| Some(a) == proto.select_arity(args.len()))
+            .ok_or_else(|| {
+                arity_error(
+                    args.len(),
+                    proto.name,
+                    proto.arities.iter().map(|a| &a.params),
+                )
+            })?;
+        let this = proto.name.map(|_| MalValue::Compiled(closure.clone()));
+        let params = &proto.arities[arity];
+        let mut locals: Vec<Slot> = bind_params(&params.params, args, this)?
+            .into_iter()
+            .map(Slot::Val)
+            .collect();
+        locals.resize_with(params.slots as usize, || Slot::Val(MalValue::Nil));
+
+        self.frames.push(Frame {
+            env: closure.env.clone(),
+            closure,
+            arity,
+            ip: 0,
+            locals,
+            base: self.stack.len(),
+            _depth: depth,
+        });
+        Ok(())
+    }
+
+    fn frame(&mut self) -> &mut Frame {
+        self.frames.last_mut().expect("No frame to run")
+    }
+
+    fn pop(&mut self) -> MalValue {
+        self.stack.pop().unwrap_or(MalValue::Nil)
+    }
+
+    /// Start of the `n` values on top of the stack, which a cache not made by the compiler may
+    /// not have pushed
+    fn top(&mut self, n: usize) -> Result<usize> {
+        let base = self.frame().base;
+        self.stack
+            .len()
+            .checked_sub(n)
+            .filter(|start| *start >= base)
+            .context("Stack underflow")
+    }
+
+    fn run(&mut self) -> Result<MalValue> {
+        loop {
+            let frame = self.frame();
+            let op = frame.arity().chunk.code[frame.ip];
+            frame.ip += 1;
+
+            match op {
+                Op::Const(i) => {
+                    let val = self.frame().arity().chunk.constants[i as usize].clone();
+                    self.stack.push(val);
+                }
+                Op::Nil => self.stack.push(MalValue::Nil),
+                Op::True => self.stack.push(MalValue::True),
+                Op::False => self.stack.push(MalValue::False),
+                Op::GetGlobal { sym, cache } => {
+                    let frame = self.frame();
+                    let val = frame.arity().chunk.caches[cache as usize]
+                        .get(&frame.env, sym)
+                        .with_context(|| anyhow!("Symbol: '{}' not found", sym))?;
+                    self.stack.push(val);
+                }
+                Op::DefGlobal(sym) | Op::DefPrivate(sym) => {
+                    let val = self.stack.last().cloned().unwrap_or(MalValue::Nil);
+                    let env = &self.frame().env;
+                    env_set_sym(env, sym, val);
+                    if let Op::DefPrivate(_) = op {
+                        namespace::set_private(env, &MalValue::Sym(sym));
+                    }
+                }
+                Op::GetLocal(slot) => {
+                    let val = self.frame().locals[slot as usize].get();
+                    self.stack.push(val);
+                }
+                Op::SetLocal(slot) => {
+                    let val = self.pop();
+                    self.frame().locals[slot as usize].set(val);
+                }
+                Op::ClearLocal(slot) => {
+                    self.frame().locals[slot as usize] = Slot::Val(MalValue::Nil);
+                }
+                Op::GetUpvalue(i) => {
+                    let val = self.frame().closure.upvalues[i as usize].borrow().clone();
+                    self.stack.push(val);
+                }
+                Op::Bind { pattern, slot } => {
+                    let val = self.pop();
+                    let frame = self.frame();
+                    let pattern = &frame.arity().chunk.constants[pattern as usize];
+                    let mut vals = vec![];
+                    destructure(pattern, val, &mut vals)
+                        .with_context(|| anyhow!("Cannot bind '{}' in let", pr_str(pattern)))?;
+                    for (i, val) in vals.into_iter().enumerate() {
+                        frame.locals[slot as usize + i].set(val);
+                    }
+                }
+                Op::Pop => {
+                    self.pop();
+                }
+                Op::Jump(target) => self.frame().ip = target as usize,
+                Op::JumpIfFalse(target) => {
+                    if let MalValue::Nil | MalValue::False = self.pop() {
+                        self.frame().ip = target as usize;
+                    }
+                }
+                Op::Call(argc) => {
+                    budget::tick()?;
+                    // The function is below its arguments
+                    let start = self.top(argc as usize + 1)? + 1;
+                    // Builtins take their arguments straight from the stack
+                    if let MalValue::Function(f) = &self.stack[start - 1] {
+                        let val = f(&self.stack[start..])?;
+                        budget::check_len(&val)?;
+                        self.stack.truncate(start - 1);
+                        self.stack.push(val);
+                        continue;
+                    }
+                    let args = Rc::new(self.stack.split_off(start));
+                    match self.pop() {
+                        MalValue::Compiled(closure) => self.push_frame(closure, args)?,
+                        f => {
+                            let val = f.apply(args)?;
+                            self.stack.push(val);
+                        }
+                    }
+                }
+                Op::Return => {
+                    let val = self.pop();
+                    let frame = self.frames.pop().expect("No frame to return from");
+                    self.stack.truncate(frame.base);
+                    if self.frames.is_empty() {
+                        return Ok(val);
+                    }
+                    self.stack.push(val);
+                }
+                Op::Closure(i) => {
+                    let frame = self.frame();
+                    let proto = frame.arity().chunk.protos[i as usize].clone();
+                    let upvalues = proto
+                        .captures
+                        .iter()
+                        .map(|c| match c {
+                            Capture::Local(slot) => frame.locals[*slot as usize].capture(),
+                            Capture::Upvalue(i) => frame.closure.upvalues[*i as usize].clone(),
+                        })
+                        .collect();
+                    let closure = Closure {
+                        proto,
+                        upvalues,
+                        env: frame.env.clone(),
+                    };
+                    self.stack.push(MalValue::Compiled(Rc::new(closure)));
+                }
+                Op::Vec(n) => {
+                    let start = self.top(n as usize)?;
+                    let vals = self.stack.split_off(start);
+                    self.stack.push(MalValue::Vec(Rc::new(vals)));
+                }
+                Op::Map(keys) => {
+                    let keys = match &self.frame().arity().chunk.constants[keys as usize] {
+                        MalValue::List(keys) => keys.clone(),
+                        v => return Err(anyhow!("Invalid map keys: {}", pr_str(v))),
+                    };
+                    let start = self.top(keys.len())?;
+                    let vals = self.stack.split_off(start);
+                    let map = keys
+                        .iter()
+                        .zip(vals)
+                        .map(|(k, v)| match k {
+                            MalValue::String(k) => Ok((k.to_string(), v)),
+                            k => Err(anyhow!("Invalid map key: {}", pr_str(k))),
+                        })
+                        .collect::<Result<MalMap>>()?;
+                    self.stack.push(MalValue::Map(Rc::new(map)));
+                }
+                Op::InNs(name) => {
+                    sandbox::check(Capability::Namespaces)?;
+                    self.frame().env = namespace::in_ns(name).env.clone();
+                    self.stack.push(MalValue::Nil);
+                }
+                Op::Ns(form) => {
+                    let ns = match &self.frame().arity().chunk.constants[form as usize] {
+                        MalValue::List(form) => namespace::ns_form(form)?,
+                        v => return Err(anyhow!("Invalid ns form: {}", pr_str(v))),
+                    };
+                    self.frame().env = ns.env.clone();
+                    self.stack.push(MalValue::Nil);
+                }
+            }
+        }
+    }
+}
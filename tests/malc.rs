@@ -0,0 +1,83 @@
+//! Encoding and decoding of the `.malc` caches
+
+use std::rc::Rc;
+
+use mal_rust::{
+    bytecode::{Op, Proto},
+    compiler::compile,
+    env::Env,
+    malc::{decode, encode, source_hash, BUILD_ID},
+    reader::read_all,
+    vm, Result,
+};
+
+const SOURCE: &str = r#"
+(def! sq (fn* [x] (* x x)))
+(defn f ([] :none) ([a & more] {:a a "more" more}))
+(let* [{:keys [a] :or {a 1}} {} [b c] (list 2 #"re+")] (list a b c nil true false))
+(ns other (:require [user :as u]))
+"#;
+
+fn protos() -> Vec<Rc<Proto>> {
+    read_all(SOURCE)
+        .unwrap()
+        .iter()
+        .map(compile)
+        .collect::<Result<_>>()
+        .unwrap()
+}
+
+#[test]
+fn decoding_gives_back_the_encoded_protos() {
+    let protos = protos();
+    let hash = source_hash(SOURCE);
+    let bytes = encode(&protos, hash).unwrap();
+    assert_eq!(decode(&bytes, hash).unwrap(), protos);
+}
+
+#[test]
+fn stale_caches_are_rejected() {
+    let hash = source_hash(SOURCE);
+    let bytes = encode(&protos(), hash).unwrap();
+
+    assert!(decode(&bytes, source_hash("(def! sq 1)")).is_err());
+    assert!(decode(&bytes[..bytes.len() - 1], hash).is_err());
+    assert!(decode(b"NOPE", hash).is_err());
+
+    // The build id follows the magic and its length
+    let mut other_build = bytes.clone();
+    assert_eq!(&other_build[8..8 + BUILD_ID.len()], BUILD_ID.as_bytes());
+    other_build[8] ^= 1;
+    assert!(decode(&other_build, hash).is_err());
+}
+
+#[test]
+fn caches_with_invalid_indices_are_rejected() {
+    let hash = source_hash(SOURCE);
+    let with_code = |code: Vec<Op>| {
+        let mut proto = (*protos()[0]).clone();
+        proto.arities[0].chunk.code = code;
+        encode(&[Rc::new(proto)], hash).unwrap()
+    };
+    assert!(decode(&with_code(vec![Op::Nil, Op::Return]), hash).is_ok());
+    for op in [
+        Op::Const(1000),
+        Op::GetLocal(1000),
+        Op::GetUpvalue(0),
+        Op::Jump(1000),
+        Op::Closure(1000),
+        Op::Ns(1000),
+    ] {
+        assert!(
+            decode(&with_code(vec![op, Op::Return]), hash).is_err(),
+            "{:?}",
+            op
+        );
+    }
+    // Running off the end of the code
+    assert!(decode(&with_code(vec![Op::Nil]), hash).is_err());
+
+    // A call without enough values on the stack is an error, not a panic
+    let protos = decode(&with_code(vec![Op::Call(3), Op::Return]), hash).unwrap();
+    assert!(vm::run(protos[0].clone(), &Rc::new(Env::new())).is_err());
+}
@@ -0,0 +1,94 @@
+//! Builtins and forms denied to sandboxed code, and the limits it runs within
+
+use mal_rust::{
+    budget::Interrupt,
+    env::{env_get_sym, env_set_sym},
+    namespace,
+    printer::pr_str,
+    sandbox::{Capability, CapabilityError, Sandbox},
+    symbol::{Symbol, MAX_SYMBOLS},
+    types::MalValue,
+};
+
+/// Capability the evaluation failed for, [None] if it succeeded or failed otherwise
+fn denied(sandbox: &Sandbox, source: &str) -> Option<Capability> {
+    let err = sandbox.eval(source).err()?;
+    err.downcast_ref::<CapabilityError>().map(|e| e.0)
+}
+
+#[test]
+fn io_needs_to_be_allowed() {
+    let sandbox = Sandbox::builder().allow_pure().build().unwrap();
+    assert_eq!(
+        denied(&sandbox, "(load-file \"x.mal\")"),
+        Some(Capability::Io)
+    );
+    assert_eq!(denied(&sandbox, "(prn 1)"), Some(Capability::Io));
+    assert_eq!(pr_str(&sandbox.eval("(+ 1 2)").unwrap()), "3");
+
+    let sandbox = Sandbox::builder()
+        .allow_pure()
+        .allow("prn")
+        .build()
+        .unwrap();
+    assert_eq!(pr_str(&sandbox.eval("(prn 1)").unwrap()), "nil");
+    assert_eq!(
+        denied(&sandbox, "(load-file \"x.mal\")"),
+        Some(Capability::Io)
+    );
+}
+
+#[test]
+fn namespaces_are_out_of_reach() {
+    let sandbox = Sandbox::builder().allow_pure().build().unwrap();
+    assert_eq!(denied(&sandbox, "(ns other)"), Some(Capability::Namespaces));
+    assert_eq!(
+        denied(&sandbox, "(in-ns other)"),
+        Some(Capability::Namespaces)
+    );
+
+    let user = namespace::in_ns(Symbol::new("user"));
+    env_set_sym(&user.env, Symbol::new("x"), MalValue::Number(1));
+    let x = env_get_sym(&user.env, Symbol::new("user/x")).unwrap();
+    assert_eq!(pr_str(&x), "1");
+    assert!(sandbox.eval("user/x").is_err());
+    // Definitions stay in the sandbox
+    sandbox.eval("(def! x 1)").unwrap();
+    assert_eq!(pr_str(&sandbox.eval("x").unwrap()), "1");
+}
+
+#[test]
+fn only_allowed_builtins_are_bound() {
+    let sandbox = Sandbox::builder().allow("+").build().unwrap();
+    assert_eq!(pr_str(&sandbox.eval("(+ 1 2)").unwrap()), "3");
+    let err = sandbox.eval("(- 1 2)").unwrap_err();
+    assert!(err.downcast_ref::<CapabilityError>().is_none(), "{}", err);
+
+    assert!(Sandbox::builder().allow("no-such-builtin").build().is_err());
+}
+
+#[test]
+fn recursion_through_builtins_stops_before_the_native_stack_overflows() {
+    let sandbox = Sandbox::builder().allow_pure().build().unwrap();
+    // Each call goes through `map`, using much more native stack than a call of the VM
+    let err = sandbox
+        .eval("(do (def! f (fn* [n] (map f [n]))) (f 1))")
+        .unwrap_err();
+    assert_eq!(err.downcast_ref(), Some(&Interrupt::StackOverflow));
+    assert_eq!(pr_str(&sandbox.eval("(+ 1 2)").unwrap()), "3");
+}
+
+#[test]
+fn sandboxed_sources_are_bounded_in_names() {
+    let sandbox = Sandbox::builder().allow_pure().build().unwrap();
+    let names: String = (0..MAX_SYMBOLS)
+        .map(|i| format!(":sandboxed-{} ", i))
+        .collect();
+    let err = sandbox.eval(&names).unwrap_err();
+    assert!(err.to_string().starts_with("Too many symbols"), "{}", err);
+    // Names already interned are read
+    assert_eq!(
+        pr_str(&sandbox.eval(":sandboxed-1").unwrap()),
+        ":sandboxed-1"
+    );
+}
@@ -0,0 +1,617 @@
+//! Runs forms through the REPL of the step4 binary, on each of its engines
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+/// Flags selecting the tree walker, the bytecode VM and the closure compiler
+const ENGINES: [&[&str]; 3] = [&[], &["--vm"], &["--closures"]];
+
+/// Feeds the lines to the REPL, returns a printed line per line
+fn repl(flags: &[&str], lines: &[&str]) -> Vec<String> {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_step4"))
+        .args(flags)
+        // The REPL saves its history in the working directory
+        .current_dir(std::env::temp_dir())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Cannot start step4");
+    let mut stdin = child.stdin.take().expect("No stdin");
+    for line in lines {
+        writeln!(stdin, "{}", line).expect("Cannot write to step4");
+    }
+    drop(stdin);
+    let out = child.wait_with_output().expect("step4 did not run");
+    assert!(out.status.success(), "step4 exited with {}", out.status);
+    String::from_utf8(out.stdout)
+        .expect("Output is not UTF-8")
+        .lines()
+        .map(str::to_string)
+        .collect()
+}
+
+/// Empty directory for the files of a test
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("mal-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).expect("Cannot create the test directory");
+    dir
+}
+
+#[test]
+fn deep_recursion_stops_with_an_error() {
+    for flags in ENGINES {
+        let out = repl(
+            flags,
+            &[
+                "(def! f (fn* () (f)))",
+                "(f)",
+                "(def! g (fn* (n) (if (= n 0) 0 (g (- n 1)))))",
+                "(g 5000)",
+                "(+ 1 2)",
+            ],
+        );
+        assert!(out[1].starts_with("Error: Stack overflow"), "{:?}", flags);
+        assert_eq!(out[3..], ["0", "3"], "{:?}", flags);
+    }
+}
+
+#[test]
+fn sort_survives_an_inconsistent_comparator() {
+    let out = repl(
+        &[],
+        &[
+            "(count (sort (fn* [a b] (- (rand-int 3) 1)) (range 2000)))",
+            "(sort (fn* [a b] (nil)) (list 1 2))",
+            "(sort-by count (list \"ccc\" \"a\" \"bb\" \"d\"))",
+        ],
+    );
+    assert_eq!(out[0], "2000");
+    assert!(out[1].starts_with("Error: "));
+    assert_eq!(out[2], r#"("a" "d" "bb" "ccc")"#);
+}
+
+#[test]
+fn functions_are_not_compared() {
+    for flags in ENGINES {
+        let out = repl(
+            flags,
+            &[
+                "(compare + -)",
+                "(sort (list [1 +] [1 -]))",
+                "(sort-by first (list [(fn* [] 1)] [(fn* [] 2)]))",
+                "(sort (fn* [a b] 0) (list + -))",
+            ],
+        );
+        assert_eq!(
+            out,
+            [
+                "Error: Functions cannot be compared: <fn +>",
+                "Error: Functions cannot be compared: <fn +>",
+                "Error: Functions cannot be compared: <closure: ([] 1)>",
+                "(<fn +> <fn ->)",
+            ],
+            "{:?}",
+            flags
+        );
+    }
+}
+
+#[test]
+fn sorted_map_survives_an_inconsistent_comparator() {
+    let out = repl(
+        &[],
+        &[
+            "(def! pairs (reduce concat (list) (map (fn* [i] (list (str i) i)) (range 500))))",
+            "(count (apply sorted-map-by (fn* [a b] (- (rand-int 3) 1)) pairs))",
+            "(sorted-map-by (fn* [a b] (nil)) \"a\" 1 \"b\" 2)",
+            "(sorted-map-by compare \"b\" 2 \"a\" 1 \"b\" 3)",
+        ],
+    );
+    assert_eq!(out[1], "500");
+    assert!(out[2].starts_with("Error: "));
+    assert_eq!(out[3], r#"{"a" 1 "b" 3}"#);
+}
+
+#[test]
+fn range_is_bounded_without_a_budget() {
+    let out = repl(
+        &[],
+        &[
+            "(count (range 100000000000000))",
+            "(range 9223372036854775805 9223372036854775807)",
+            "(range 10 0 -3)",
+        ],
+    );
+    assert_eq!(out[0], "Error: Too large, 100000000000000 items");
+    assert_eq!(out[1], "(9223372036854775805 9223372036854775806)");
+    assert_eq!(out[2], "(10 7 4 1)");
+}
+
+#[test]
+fn or_defaults_are_evaluated_only_for_missing_keys() {
+    for flags in ENGINES.iter().chain([&["--optimize"][..]].iter()) {
+        let out = repl(
+            flags,
+            &[
+                "(let* [{:keys [c d] :or {d (+ 2 3)}} {:c 3}] (list c d))",
+                "(let* [{:keys [a] :or {a 1}} {:a nil}] a)",
+                "(let* [{:keys [a b] :or {a 1 b (+ a 10)}} {}] (list a b))",
+                "(let* [{:strs [s] :or {s \"x\"}} {\"s\" \"y\"}] s)",
+                "(def! n 0)",
+                "(let* [{:keys [a] :or {a (def! n (+ n 1))}} {:a 5}] (list a n))",
+                "((fn* [{:keys [a b] :or {b 7}}] (list a b)) {:a 1})",
+                "((fn* [x & {:keys [k] :or {k :none}}] (list x k)) 1)",
+                "(let* [x 3 {:keys [a] :or {a x}} nil] a)",
+                "(let* [{:keys [a] :or {:a 1}} {}] a)",
+            ],
+        );
+        assert_eq!(
+            out,
+            [
+                "(3 5)",
+                "nil",
+                "(1 11)",
+                "\"y\"",
+                "0",
+                "(5 0)",
+                "(1 7)",
+                "(1 :none)",
+                "3",
+                "Error: :or defaults are keyed by symbol, got: :a",
+            ],
+            "{:?}",
+            flags
+        );
+    }
+}
+
+#[test]
+fn ctrl_c_stops_the_form_and_keeps_the_definitions() {
+    for flags in ENGINES {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_step4"))
+            .args(flags)
+            .current_dir(std::env::temp_dir())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Cannot start step4");
+        let mut stdin = child.stdin.take().expect("No stdin");
+        let mut stdout = BufReader::new(child.stdout.take().expect("No stdout"));
+        let mut line = || {
+            let mut line = String::new();
+            stdout.read_line(&mut line).expect("Cannot read from step4");
+            line.trim_end().to_string()
+        };
+
+        // Printed once Ctrl-C is handled
+        writeln!(stdin, "(def! n 1000)").unwrap();
+        assert_eq!(line(), "1000", "{:?}", flags);
+        writeln!(
+            stdin,
+            "(def! f (fn* [_] (count (map (fn* [x] x) (range n)))))"
+        )
+        .unwrap();
+        line();
+        // A billion calls
+        writeln!(stdin, "(map (fn* [_] (map f (range n))) (range n))").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        let killed = Command::new("kill")
+            .args(["-INT", &child.id().to_string()])
+            .status()
+            .expect("Cannot run kill");
+        assert!(killed.success());
+        assert_eq!(line(), "Error: Interrupted", "{:?}", flags);
+
+        writeln!(stdin, "(+ n 1)").unwrap();
+        assert_eq!(line(), "1001", "{:?}", flags);
+        drop(stdin);
+        assert!(child.wait().expect("step4 did not run").success());
+    }
+}
+
+#[test]
+fn files_run_on_the_selected_engine() {
+    for flags in ENGINES.iter().chain([&["--optimize"][..]].iter()) {
+        let dir = test_dir("files");
+        let file = dir.join("sq.mal");
+        fs::write(&file, "(def! sq (fn* [x] (* x x)))\n(prn (sq 7))\n").unwrap();
+        let out = Command::new(env!("CARGO_BIN_EXE_step4"))
+            .args(*flags)
+            .arg(&file)
+            .output()
+            .expect("step4 did not run");
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "49\n", "{:?}", flags);
+        // Only the VM uses the cache
+        assert_eq!(
+            dir.join("sq.malc").exists(),
+            *flags == ["--vm"],
+            "{:?}",
+            flags
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+#[test]
+fn load_file_runs_on_the_selected_engine() {
+    for flags in ENGINES.iter().chain([&["--vm", "--optimize"][..]].iter()) {
+        let dir = test_dir("load");
+        let file = dir.join("sq.mal");
+        fs::write(&file, "(def! sq (fn* [x] (* x x)))\n").unwrap();
+        let load = format!("(load-file {:?})", file.display().to_string());
+        let out = repl(flags, &[&load, "(sq 7)"]);
+        assert_eq!(out, ["nil", "49"], "{:?}", flags);
+        assert_eq!(
+            dir.join("sq.malc").exists(),
+            *flags == ["--vm"],
+            "{:?}",
+            flags
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+#[test]
+fn deterministic_runs_give_the_same_output() {
+    let dir = test_dir("deterministic");
+    let file = dir.join("random.mal");
+    fs::write(
+        &file,
+        "(prn (map (fn* [_] (rand-int 1000000)) (range 5)))\n\
+         (def! t (time-ms))\n\
+         (prn (- (time-ms) t))\n\
+         (prn (rand-int 1000000) t)\n",
+    )
+    .unwrap();
+    let run = |flags: &[&str]| {
+        let out = Command::new(env!("CARGO_BIN_EXE_step4"))
+            .args(flags)
+            .arg(&file)
+            .output()
+            .expect("step4 did not run");
+        assert!(out.status.success(), "{:?}", flags);
+        out.stdout
+    };
+    for flags in ENGINES {
+        let flags = [&["--deterministic"][..], flags].concat();
+        let first = run(&flags);
+        assert!(!first.is_empty());
+        assert_eq!(first, run(&flags), "{:?}", flags);
+        // The seed changes the random numbers
+        let seeded = [&["--deterministic=7"][..], &flags[1..]].concat();
+        assert_ne!(first, run(&seeded), "{:?}", flags);
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn failed_runs_are_not_cached() {
+    let dir = test_dir("failed");
+    let file = dir.join("fails.mal");
+    fs::write(&file, "(def! x 1)\n(undefined)\n").unwrap();
+    let out = Command::new(env!("CARGO_BIN_EXE_step4"))
+        .arg("--vm")
+        .arg(&file)
+        .output()
+        .expect("step4 did not run");
+    assert!(!out.status.success());
+    assert!(!dir.join("fails.malc").exists());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn replacements_refer_to_groups_by_number() {
+    let out = repl(
+        &[],
+        &[
+            r#"(re-replace #"(a)" "aaa" "$1b")"#,
+            r#"(re-replace #"(a)" "aaa" "${1}b")"#,
+            r#"(replace "a1" #"(?<l>[a-z])(\d)" "$2${l}$$")"#,
+            r#"(replace "a.a" "." "$1")"#,
+        ],
+    );
+    assert_eq!(out, [r#""ababab""#, r#""ababab""#, r#""1a$""#, r#""a$1a""#]);
+}
+
+#[test]
+fn nothing_is_found_past_the_end_of_a_string() {
+    let out = repl(
+        &[],
+        &[
+            r#"(index-of "abc" "" 5)"#,
+            r#"(index-of "abc" "" 3)"#,
+            r#"(index-of "abc" "c" 1)"#,
+            r#"(index-of "abc" "a" 4)"#,
+        ],
+    );
+    assert_eq!(out, ["nil", "3", "2", "nil"]);
+}
+
+#[test]
+fn str_prints_regexes_as_their_patterns() {
+    let out = repl(
+        &[],
+        &[
+            r#"(str #"a+b" "c")"#,
+            r#"(str [#"d"])"#,
+            r#"(format "%s" #"e*")"#,
+            r#"#"f""#,
+        ],
+    );
+    assert_eq!(out, [r#""a+bc""#, r#""[d]""#, r#""e*""#, r#"#"f""#]);
+}
+
+#[test]
+fn format_pads_to_the_width() {
+    let out = repl(
+        &[],
+        &[
+            r#"(format "[%5d|%-5d|%05d|%05d|%2d]" 42 42 42 -42 123)"#,
+            r#"(format "[%4s|%-4s|%%]" "ab" :c)"#,
+            r#"(format "%0s" "ab")"#,
+            r#"(format "%99999999999999999999d" 1)"#,
+        ],
+    );
+    assert_eq!(
+        out,
+        [
+            r#""[   42|42   |00042|-0042|123]""#,
+            r#""[  ab|:c  |%]""#,
+            "Error: Unknown format directive %0s",
+            "Error: Format width too large: number too large to fit in target type",
+        ]
+    );
+}
+
+#[test]
+fn maps_need_keys_and_values_in_pairs() {
+    let out = repl(
+        &[],
+        &[
+            "(hash-map :a 1 :b)",
+            "{:a 1 :b}",
+            "(sorted-map :a)",
+            "(hash-map :a 1)",
+        ],
+    );
+    assert_eq!(
+        out,
+        [
+            "Error: hash-map needs an even number of key/value arguments, got 3",
+            "Error: A map needs an even number of keys and values, got 3",
+            "Error: A sorted map needs an even number of key/value arguments, got 1",
+            "{:a 1}",
+        ]
+    );
+}
+
+#[test]
+fn names_made_at_runtime_are_bounded() {
+    let out = repl(
+        &[],
+        &[
+            "(keyword \"fresh\")",
+            "(count (map (fn* [i] (keyword (str \"k\" i))) (range 70000)))",
+            "(keyword \"def!\")",
+            "(symbol \"not-interned-yet\")",
+            "(def! not-interned-yet 1)",
+        ],
+    );
+    assert_eq!(out[0], ":fresh");
+    assert_eq!(out[1], "Error: Too many symbols, 65536 names interned");
+    assert_eq!(out[2], ":def!");
+    assert!(out[3].starts_with("Error: Too many symbols"));
+    // The source still interns its names
+    assert_eq!(out[4], "1");
+}
+
+#[test]
+fn names_are_readable() {
+    let out = repl(
+        &[],
+        &[
+            r#"(keyword "a b")"#,
+            r#"(symbol "x)")"#,
+            r#"(keyword "")"#,
+            r#"(keyword "ns/a-b?")"#,
+            r#"(symbol "ns/a-b?")"#,
+        ],
+    );
+    assert_eq!(
+        out,
+        [
+            r#"Error: Invalid keyword name: "a b""#,
+            r#"Error: Invalid symbol name: "x)""#,
+            r#"Error: Invalid keyword name: """#,
+            ":ns/a-b?",
+            "ns/a-b?",
+        ]
+    );
+}
+
+#[test]
+fn predicates_of_missing_features_are_false() {
+    // There are no macros and no atoms yet, the mal tests check `macro?` and `atom?` on values
+    let out = repl(
+        &[],
+        &[
+            "(macro? +)",
+            "(macro? {})",
+            "(atom? 1)",
+            "(atom? :a)",
+            "(atom?)",
+        ],
+    );
+    assert_eq!(out[..4], ["false", "false", "false", "false"]);
+    assert!(out[4].starts_with("Error: "), "{}", out[4]);
+}
+
+#[test]
+fn mod_reports_overflows_like_rem() {
+    let out = repl(
+        &[],
+        &[
+            "(mod -9223372036854775808 -1)",
+            "(rem -9223372036854775808 -1)",
+            "(mod -7 2)",
+            "(mod 7 -2)",
+        ],
+    );
+    assert_eq!(
+        out,
+        [
+            "Error: Integer overflow in mod",
+            "Error: Integer overflow in rem",
+            "1",
+            "-1"
+        ]
+    );
+}
+
+#[test]
+fn functions_print_the_same_on_every_engine() {
+    let lines = [
+        "(fn* [n] (fn* [m] (+ n m)))",
+        "((fn* [n] (fn* [m] (+ n m))) 1)",
+        "(def! f (fn* f ([] 0) ([a & more] (let* [{:keys [k] :or {k a}} {}] k))))",
+        "(defn g [{:keys [x] :or {x 1}}] (fn* [] x))",
+        "(g {})",
+        "(let* [h (fn* [] h)] (h))",
+    ];
+    let expected = repl(&[], &lines);
+    assert_eq!(expected[0], "<closure: ([n] (fn* [m] (+ n m)))>");
+    assert_eq!(expected[1], "<closure: ([m] (+ n m))>");
+    assert_eq!(expected[4], "<closure: ([] x)>");
+    for flags in &ENGINES[1..] {
+        assert_eq!(repl(flags, &lines), expected, "{:?}", flags);
+    }
+}
+
+#[test]
+fn optimiser_folds_only_builtins_that_cannot_change_or_fail() {
+    let out = repl(
+        &[],
+        &[
+            r#"(optimize "(+ 1 (* 2 3))")"#,
+            r#"(optimize "(let* [+ -] (+ 1 2))")"#,
+            r#"(optimize "(fn* [+] (+ 1 2))")"#,
+            r#"(optimize "(do (def! inc dec) (inc 1))")"#,
+            r#"(optimize "(let* [x 1] (let* [x 2] x))")"#,
+            r#"(optimize "(+ 9223372036854775807 1)")"#,
+            r#"(optimize "(/ 1 0)")"#,
+            "(def! g (fn* [] (def! + -)))",
+            r#"(optimize "(do (g) (+ 1 2))")"#,
+            r#"(optimize "(fn* [x] (+ x (* 2 3)))")"#,
+        ],
+    );
+    // `optimize` prints the form and returns nil
+    let forms: Vec<_> = out
+        .iter()
+        .filter(|l| *l != "nil" && !l.starts_with("<closure"))
+        .collect();
+    assert_eq!(
+        forms,
+        [
+            "7",
+            "(let* [+ -] (+ 1 2))",
+            "(fn* [+] (+ 1 2))",
+            "(do (def! inc dec) (inc 1))",
+            "(let* [x 1] 2)",
+            "(+ 9223372036854775807 1)",
+            "(/ 1 0)",
+            "(do (g) (+ 1 2))",
+            "(fn* [x] (+ x (* 2 3)))",
+        ]
+    );
+
+    let out = repl(
+        &["--optimize"],
+        &[
+            "(let* [+ -] (+ 1 2))",
+            "(+ 9223372036854775807 1)",
+            "(/ 1 0)",
+            "(def! g (fn* [] (def! + -)))",
+            "(def! h (fn* [] (* 2 3)))",
+            "(do (g) (+ 1 2))",
+            "(def! * list)",
+            "(h)",
+        ],
+    );
+    assert_eq!(
+        out[..3],
+        [
+            "-1",
+            "Error: Integer overflow in +",
+            "Error: Divide by zero"
+        ]
+    );
+    // Redefined at runtime, after the forms were optimised
+    assert_eq!(out[5], "-1");
+    assert_eq!(out[7], "(2 3)");
+}
+
+#[test]
+fn gc_collects_let_recursive_closures() {
+    for flags in ENGINES {
+        let out = repl(
+            flags,
+            &[
+                "(heap-stats)",
+                "(let* [f (fn* [n] (if (= n 0) 0 (f (- n 1))))] (f 3))",
+                "(gc)",
+                "(gc)",
+                "(heap-stats)",
+                "(def! keep (let* [g (fn* [] g)] g))",
+                "(gc)",
+                "(= (keep) keep)",
+            ],
+        );
+        // The closure and the frame of the `let*` point to each other
+        assert_ne!(out[2], "0", "{:?}", flags);
+        assert_eq!(out[3], "0", "{:?}", flags);
+        let envs = |stats: &str| stats.split(" :tracked").next().unwrap().to_string();
+        assert_eq!(envs(&out[4]), envs(&out[0]), "{:?}", flags);
+        // A cycle still reachable from the globals stays
+        assert_eq!(out[6..], ["0", "true"], "{:?}", flags);
+    }
+}
+
+#[test]
+fn engines_agree() {
+    let lines = [
+        "(def! fib (fn* [n] (if (<= n 1) n (+ (fib (- n 1)) (fib (- n 2))))))",
+        "(fib 15)",
+        "(defn f ([] :none) ([a] (list a)) ([a & more] (list a more)))",
+        "(list (f) (f 1) (f 1 2 3))",
+        "(let* [[a [b c] & d] (list 1 (list 2 3) 4 5) {:keys [k] :strs [s]} {:k 6 \"s\" 7}] (list a b c d k s))",
+        "(let* [even (fn* [n] (if (= n 0) true (odd (- n 1)))) odd (fn* [n] (if (= n 0) false (even (- n 1))))] (even 10))",
+        "(def! counter (fn* [n] (fn* [m] (+ n m))))",
+        "((counter 3) 4)",
+        "(map (fn* [x] (* x x)) (vector 1 2 3))",
+        "(reduce + 0 (filter (fn* [x] (= 0 (mod x 2))) (range 10)))",
+        "(assoc {:a 1} :b (str \"x\" 2))",
+        "(f 1 2 3 4 5 6 7 8 9)",
+        "(undefined 1)",
+        "(fib \"a\")",
+        "((fn* [a b] a) 1)",
+        "(ns other (:require [user :as u]))",
+        "(u/fib 10)",
+        "(in-ns user)",
+        "(do (def! x 1) (def! x (+ x 1)) x)",
+    ];
+    let expected = repl(&[], &lines);
+    assert_eq!(expected[1], "610");
+    assert_eq!(expected[4], "(1 2 3 (4 5) 6 7)");
+    assert_eq!(expected[9], "20");
+    assert_eq!(expected[16], "55");
+    for flags in &ENGINES[1..] {
+        assert_eq!(repl(flags, &lines), expected, "{:?}", flags);
+    }
+}
@@ -0,0 +1,17 @@
+//! Layout of the values, cloned all the time by every engine
+
+use std::mem::size_of;
+
+use mal_rust::{symbol::Symbol, types::MalValue};
+
+#[test]
+fn values_fit_in_three_words() {
+    assert_eq!(size_of::<MalValue>(), 24);
+    // Empty variants leave room for the niche of an option
+    assert_eq!(size_of::<Option<MalValue>>(), 24);
+}
+
+#[test]
+fn symbols_are_ids() {
+    assert_eq!(size_of::<Symbol>(), 4);
+}